@@ -0,0 +1,426 @@
+use crate::core::{self, ClientConfig, Commands};
+use crate::protocol::{self, Message, Receiver, Sender};
+use crate::{client_shell, secret, Error};
+use log::{debug, error, info, warn};
+use std::io::Read;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+// `upsync agent` runs on the client inside the user's session (as a systemd
+// user service) and shows the popup there, instead of the server reaching in
+// over SSH with DISPLAY=:0. The server connects when it has something to say;
+// see protocol.rs for what goes over the wire.
+const DEFAULT_LISTEN: &str = "0.0.0.0:7654";
+
+// A side waiting on the other sends a heartbeat this often and gives up after
+// three go unanswered.
+const HEARTBEAT: time::Duration = time::Duration::from_secs(10);
+
+// How long the server waits for a command to be acknowledged or run.
+const REPLY_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+// The server needs one or two at a time; more are refused so peers on the LAN
+// can't tie up threads.
+const MAX_CONNECTIONS: usize = 8;
+
+pub(crate) fn run(args: &[String]) -> Result<(), Error> {
+    let listen = core::get_flag(args, "--listen").unwrap_or(DEFAULT_LISTEN);
+    let key = core::get_flag(args, "--key")
+        .ok_or_else(|| Error::Usage("--key is required, e.g. --key file:/path".to_string()))?;
+    let key = Arc::new(secret::resolve(key)?);
+    protocol::parse_key(&key)?;
+    let commands = Arc::new(client_shell::commands(args)?);
+
+    let listener = TcpListener::bind(listen).map_err(Error::Network)?;
+    info!("Agent listening on {}", listen);
+
+    // The popup on screen, if any, so a later connection can close it.
+    let popup: Arc<Mutex<Option<Child>>> = Arc::new(Mutex::new(None));
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Could not accept a connection: {}", err);
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown".to_string(), |peer| peer.to_string());
+        if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            open.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "{}: refused, {} connections are open",
+                peer, MAX_CONNECTIONS
+            );
+            continue;
+        }
+
+        let (key, commands, popup) = (key.clone(), commands.clone(), popup.clone());
+        let open = open.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve(stream, &key, commands, popup) {
+                warn!("{}: {}", peer, err);
+            }
+            open.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+fn serve(
+    stream: TcpStream,
+    key: &str,
+    commands: Arc<Commands>,
+    popup: Arc<Mutex<Option<Child>>>,
+) -> Result<(), Error> {
+    let (sender, mut receiver) = protocol::accept(stream, key)?;
+    let sender = Arc::new(Mutex::new(sender));
+    receiver.set_timeout(Some(HEARTBEAT * 3))?;
+
+    loop {
+        let envelope = match receiver.recv() {
+            Ok(envelope) => envelope,
+            Err(err) if protocol::closed(&err) => return Ok(()),
+            Err(err) if protocol::timed_out(&err) => {
+                return Err(Error::Command("the server went silent".to_string()))
+            }
+            Err(err) => return Err(err),
+        };
+        if !matches!(envelope.message, Message::Ack { .. }) {
+            send(&sender, &Message::Ack { id: envelope.id })?;
+        }
+
+        match envelope.message {
+            Message::PowerLost => info!("The server lost power"),
+            Message::Countdown {
+                default,
+                seconds,
+                actions,
+                blockers,
+            } => {
+                let mut gui = Command::new(core::GUI_APPNAME);
+                gui.env("MOD", "gui")
+                    .env("AGENT", "yes")
+                    .env("DEFAULT", &default)
+                    .env("SEC", seconds.to_string())
+                    .env("ACTIONS", actions.join(","))
+                    .env("BLOCKERS", &blockers);
+                // Heartbeats and a cancel still have to get through while the
+                // popup is up.
+                let (sender, commands, popup) = (sender.clone(), commands.clone(), popup.clone());
+                thread::spawn(move || {
+                    if let Err(err) = countdown(gui, &sender, &commands, &popup) {
+                        warn!("{}", err);
+                    }
+                });
+            }
+            Message::Command { action } => {
+                let error = perform(&commands, &action).err().map(|err| err.to_string());
+                send(&sender, &Message::Done { action, error })?;
+            }
+            Message::Cancel => close(&popup),
+            Message::Restored => {
+                info!("Power is back at the server");
+                close(&popup);
+            }
+            Message::Heartbeat | Message::Ack { .. } => {}
+            other => warn!("Unexpected message: {:?}", other),
+        }
+    }
+}
+
+fn send(sender: &Mutex<Sender>, message: &Message) -> Result<u64, Error> {
+    sender
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .send(message)
+}
+
+fn countdown(
+    gui: Command,
+    sender: &Mutex<Sender>,
+    commands: &Commands,
+    popup: &Mutex<Option<Child>>,
+) -> Result<(), Error> {
+    let Some(action) = show(gui, popup)? else {
+        info!("The popup was closed before a choice was made");
+        return Ok(());
+    };
+    send(
+        sender,
+        &Message::Choice {
+            action: action.clone(),
+        },
+    )?;
+    if action != "ignore" {
+        let error = perform(commands, &action).err().map(|err| err.to_string());
+        send(sender, &Message::Done { action, error })?;
+    }
+    Ok(())
+}
+
+// Shows the popup and returns the action it printed, None if it was closed
+// without one.
+fn show(mut gui: Command, popup: &Mutex<Option<Child>>) -> Result<Option<String>, Error> {
+    let mut child = gui.stdout(Stdio::piped()).spawn()?;
+    let mut stdout = child.stdout.take();
+    *popup.lock().unwrap_or_else(|err| err.into_inner()) = Some(child);
+
+    let mut output = String::new();
+    if let Some(stdout) = stdout.as_mut() {
+        stdout.read_to_string(&mut output)?;
+    }
+    if let Some(mut child) = popup.lock().unwrap_or_else(|err| err.into_inner()).take() {
+        child.wait()?;
+    }
+    Ok(output
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .map(str::to_string))
+}
+
+fn close(popup: &Mutex<Option<Child>>) {
+    if let Some(mut child) = popup.lock().unwrap_or_else(|err| err.into_inner()).take() {
+        info!("Closing the popup");
+        if let Err(err) = child.kill().and_then(|()| child.wait().map(drop)) {
+            warn!("Could not close the popup: {}", err);
+        }
+    }
+}
+
+fn perform(commands: &Commands, action: &str) -> Result<(), Error> {
+    let command = commands
+        .command(action)
+        .ok_or_else(|| Error::Command(format!("no command for {}", action)))?;
+    info!("Running {}", command);
+    match core::run_command(&command)? {
+        true => Ok(()),
+        false => Err(Error::Command(format!("'{}' failed", command))),
+    }
+}
+
+fn connect(config: &ClientConfig) -> Result<(Sender, Receiver), Error> {
+    let address = config
+        .agent
+        .to_socket_addrs()
+        .map_err(Error::Network)?
+        .next()
+        .ok_or_else(|| {
+            Error::Config(format!("{}: no address for {}", config.name, config.agent))
+        })?;
+    let stream = TcpStream::connect_timeout(&address, time::Duration::from_secs(3))
+        .map_err(Error::Network)?;
+    // An agent that hangs up or stays silent during the handshake is as
+    // unreachable as one that isn't listening.
+    protocol::connect(stream, &secret::resolve(&config.agent_key)?).map_err(|err| match err {
+        Error::Io(err) => Error::Network(err),
+        err => err,
+    })
+}
+
+// Whether the agent couldn't be reached at all, e.g. because nobody is logged
+// in to run it. Everything sent over SSH still works then.
+pub(crate) fn unreachable(err: &Error) -> bool {
+    matches!(err, Error::Network(_))
+}
+
+// Sends the messages and waits until the agent has acknowledged them all.
+fn deliver(
+    config: &ClientConfig,
+    sender: &mut Sender,
+    receiver: &mut Receiver,
+    messages: &[Message],
+) -> Result<(), Error> {
+    receiver.set_timeout(Some(REPLY_TIMEOUT))?;
+    let mut pending = messages
+        .iter()
+        .map(|message| sender.send(message))
+        .collect::<Result<Vec<u64>, Error>>()?;
+    while !pending.is_empty() {
+        match receiver.recv()?.message {
+            Message::Ack { id } => pending.retain(|pending| *pending != id),
+            other => report(config, other),
+        }
+    }
+    Ok(())
+}
+
+// Logs what the agent tells us about the user's choice and its result.
+fn report(config: &ClientConfig, message: Message) {
+    match message {
+        Message::Choice { action } => info!("{} chose {}", config.name, action),
+        Message::Done {
+            action,
+            error: None,
+        } => info!("{} ran {}", config.name, action),
+        Message::Done {
+            action,
+            error: Some(err),
+        } => error!("{} could not {}: {}", config.name, action, err),
+        Message::Ack { .. } | Message::Heartbeat => {}
+        other => warn!("Unexpected message from {}: {:?}", config.name, other),
+    }
+}
+
+// Asks the agent to show the popup. Its answers are logged as they arrive,
+// until it has acted or the client went to sleep.
+pub(crate) fn popup(
+    config: &ClientConfig,
+    default: &str,
+    actions: &[&str],
+    blockers: &[String],
+) -> Result<(), Error> {
+    let (mut sender, mut receiver) = connect(config)?;
+    let countdown = Message::Countdown {
+        default: default.to_string(),
+        seconds: config.default_delay,
+        actions: actions.iter().map(|action| action.to_string()).collect(),
+        blockers: blockers.join("; "),
+    };
+    deliver(
+        config,
+        &mut sender,
+        &mut receiver,
+        &[Message::PowerLost, countdown],
+    )?;
+
+    let config = config.clone();
+    thread::spawn(move || {
+        if let Err(err) = receiver.set_timeout(Some(HEARTBEAT)) {
+            warn!("{}", err);
+            return;
+        }
+        let mut missed = 0;
+        loop {
+            match receiver.recv() {
+                Ok(envelope) => {
+                    missed = 0;
+                    let finished = match &envelope.message {
+                        Message::Done { .. } => true,
+                        Message::Choice { action } => action == "ignore",
+                        _ => false,
+                    };
+                    if !matches!(envelope.message, Message::Ack { .. }) {
+                        // The client may be going down already.
+                        let _ = sender.send(&Message::Ack { id: envelope.id });
+                    }
+                    report(&config, envelope.message);
+                    if finished {
+                        break;
+                    }
+                }
+                Err(err) if protocol::timed_out(&err) && missed < 3 => {
+                    missed += 1;
+                    if sender.send(&Message::Heartbeat).is_err() {
+                        break;
+                    }
+                }
+                // Expected when the client goes to sleep.
+                Err(err) => {
+                    debug!("Connection to the agent on {} ended: {}", config.name, err);
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// Has the agent run a power state and waits for the result. A connection
+// that drops meanwhile counts as success, the client may be going down.
+pub(crate) fn request(config: &ClientConfig, action: &str) -> Result<(), Error> {
+    let (mut sender, mut receiver) = connect(config)?;
+    let command = Message::Command {
+        action: action.to_string(),
+    };
+    deliver(config, &mut sender, &mut receiver, &[command])?;
+    loop {
+        match receiver.recv() {
+            Ok(envelope) => match envelope.message {
+                Message::Done {
+                    error: Some(err), ..
+                } => return Err(Error::Command(err)),
+                Message::Done { error: None, .. } => return Ok(()),
+                other => report(config, other),
+            },
+            Err(err) if protocol::closed(&err) || protocol::timed_out(&err) => {
+                debug!(
+                    "Connection to the agent on {} dropped: {}",
+                    config.name, err
+                );
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Tells the agent power is back, closing a popup that may still be open.
+pub(crate) fn power_restored(config: &ClientConfig, popup_open: bool) {
+    let config = config.clone();
+    thread::spawn(move || {
+        let mut messages = vec![Message::Restored];
+        if popup_open {
+            messages.insert(0, Message::Cancel);
+        }
+        let sent = connect(&config).and_then(|(mut sender, mut receiver)| {
+            deliver(&config, &mut sender, &mut receiver, &messages)
+        });
+        if let Err(err) = sent {
+            debug!("Could not tell the agent on {}: {}", config.name, err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &str = "8d2ef6c6a0b1d4e37f5c9a1b2c3d4e5f60718293a4b5c6d7e8f9a0b1c2d3e4f5";
+
+    #[test]
+    fn runs_commands_for_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ClientConfig {
+            name: "desktop".to_string(),
+            agent: listener.local_addr().unwrap().to_string(),
+            agent_key: format!("plain:{}", KEY),
+            ..ClientConfig::default()
+        };
+        let commands = Arc::new(Commands {
+            suspend: "true".to_string(),
+            hibernate: "false".to_string(),
+            ..Commands::default()
+        });
+        let agent = thread::spawn(move || {
+            (0..3)
+                .map(|_| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let popup = Arc::new(Mutex::new(None));
+                    serve(stream, KEY, commands.clone(), popup).is_ok()
+                })
+                .collect::<Vec<bool>>()
+        });
+
+        assert!(request(&config, "suspend").is_ok());
+        assert!(matches!(
+            request(&config, "hibernate"),
+            Err(Error::Command(_))
+        ));
+        let wrong_key = ClientConfig {
+            agent_key: format!("plain:{}", KEY.replace('8', "9")),
+            ..config.clone()
+        };
+        assert!(matches!(
+            request(&wrong_key, "suspend"),
+            Err(Error::Auth(_))
+        ));
+        assert_eq!(agent.join().unwrap(), [true, true, false]);
+    }
+}
@@ -19,7 +19,12 @@ fn main() {
         .and_then(|s| s.parse().ok())
         .unwrap_or(30);
 
-    let message = format!("System will {} in {} seconds", default_action, sec);
+    let mut message = format!("System will {} in {} seconds", default_action, sec);
+    if let Ok(blockers) = env::var("BLOCKERS") {
+        if !blockers.is_empty() {
+            message.push_str(&format!("\nStill running: {}", blockers));
+        }
+    }
 
     run_gui(message, sec);
 }
@@ -91,7 +96,7 @@ fn popup(app: &Application, defaults: String) {
         #[strong]
         window,
         move |_| {
-            close_app(&window, "ignore");
+            choose(&window, "ignore");
         }
     ));
 
@@ -99,7 +104,7 @@ fn popup(app: &Application, defaults: String) {
         #[strong]
         window,
         move |_| {
-            close_app(&window, "systemctl suspend");
+            choose(&window, "suspend");
         }
     ));
 
@@ -107,7 +112,7 @@ fn popup(app: &Application, defaults: String) {
         #[strong]
         window,
         move |_| {
-            close_app(&window, "systemctl hibernate");
+            choose(&window, "hibernate");
         }
     ));
 
@@ -115,13 +120,37 @@ fn popup(app: &Application, defaults: String) {
         #[strong]
         window,
         move |_| {
-            close_app(&window, "systemctl poweroff");
+            choose(&window, "poweroff");
         }
     ));
 
-    gtk_box.append(&button_sleep);
-    gtk_box.append(&button_hibernate);
-    gtk_box.append(&button_shutdown);
+    // The server passes the actions the client supports; show them all when
+    // started by hand.
+    let actions = env::var("ACTIONS")
+        .unwrap_or_else(|_| "suspend,hybrid-sleep,hibernate,poweroff".to_string());
+    let supported = |action: &str| actions.split(',').any(|a| a == action);
+
+    if supported("suspend") {
+        gtk_box.append(&button_sleep);
+    }
+    if supported("hybrid-sleep") {
+        let button_hybrid = Button::builder().label("Hybrid sleep").build();
+        button_hybrid.connect_clicked(clone!(
+            #[strong]
+            window,
+            move |_| {
+                choose(&window, "hybrid-sleep");
+            }
+        ));
+        button_hybrid.set_size_request(button_width, button_height);
+        gtk_box.append(&button_hybrid);
+    }
+    if supported("hibernate") {
+        gtk_box.append(&button_hibernate);
+    }
+    if supported("poweroff") {
+        gtk_box.append(&button_shutdown);
+    }
 
     if env::var("REBOOT").as_deref() == Ok("yes") {
         let button_reboot = Button::builder().label("reboot").build();
@@ -147,7 +176,11 @@ fn popup(app: &Application, defaults: String) {
 
 fn default() {
     let default = env::var("DEFAULT").unwrap_or_else(|_| "shutdown".to_string());
-    let command = format!("systemctl {}", core::get_default(&default));
+    if agent() {
+        println!("{}", core::get_default(&default));
+        std::process::exit(0);
+    }
+    let command = action_command(&core::get_default(&default));
 
     match core::run_command(&command) {
         Ok(result) => println!("{}", result),
@@ -157,6 +190,28 @@ fn default() {
     }
 }
 
+// Started by `upsync agent`, which runs the choice itself and reports it to
+// the server; the popup only prints it.
+fn agent() -> bool {
+    env::var("AGENT").as_deref() == Ok("yes")
+}
+
+fn choose(window: &ApplicationWindow, action: &str) {
+    if agent() {
+        println!("{}", action);
+        window.close();
+    } else if action == "ignore" {
+        close_app(window, action);
+    } else {
+        close_app(window, &action_command(action));
+    }
+}
+
+// The server passes the client's own commands; systemctl when started by hand.
+fn action_command(action: &str) -> String {
+    env::var(core::command_env(action)).unwrap_or_else(|_| format!("systemctl {}", action))
+}
+
 fn close_app(app: &ApplicationWindow, mut action: &str) {
     println!("{}", action);
 
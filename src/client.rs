@@ -0,0 +1,1020 @@
+use crate::{agent, config, core, secret, state, Error};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ssh2::{ErrorCode, Session};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{IpAddr, TcpStream as TcpStreamSTD, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::{thread, time};
+
+use core::ClientConfig;
+
+pub(crate) fn status(config: &ClientConfig) -> bool {
+    match core::device_status(&config.ip) {
+        Ok(status) => status,
+        Err(err) => {
+            error!("{}", err);
+            info!("Assuming device is offline");
+            false
+        }
+    }
+}
+
+// Opens the popup or applies the default behaviour directly and returns what
+// was sent ("popup" or the power state). Only power states the client
+// supports are offered or requested; blockers are shown in the popup.
+pub(crate) fn send_device_to(config: &ClientConfig, blockers: &[String]) -> Result<String, Error> {
+    let capabilities = &capabilities(config);
+    let action = core::get_default_server(&config.default_behaviour);
+    let popup = config.popup && (!config.skip_popup_when_idle || someone_present(config));
+    if !popup && action.is_empty() {
+        info!("{} is set to ignore power loss", config.name);
+        return Ok(action);
+    }
+
+    match popup {
+        true => match show_popup(config, capabilities, &action, blockers) {
+            Ok(()) => {
+                info!("popup open surcess");
+                Ok("popup".to_string())
+            }
+            Err(err) => {
+                error!("popup open error: {}", err);
+                Err(err)
+            }
+        },
+
+        false => match enter_power_state(config, &action, capabilities) {
+            Ok(action) => {
+                info!("Device send to {}", action);
+                Ok(action)
+            }
+            Err(err) => {
+                error!("{}", err);
+                Err(err)
+            }
+        },
+    }
+}
+
+// Shows the popup through the agent, or over SSH when there is none or it
+// can't be reached.
+fn show_popup(
+    config: &ClientConfig,
+    capabilities: &Capabilities,
+    action: &str,
+    blockers: &[String],
+) -> Result<(), Error> {
+    // An unsupported default falls back like a direct action would.
+    let default = POWER_STATES
+        .iter()
+        .skip_while(|state| **state != action)
+        .find(|state| available(config, capabilities, state))
+        .unwrap_or(&"poweroff");
+    let actions: Vec<&str> = capabilities
+        .actions()
+        .into_iter()
+        .filter(|action| available(config, capabilities, action))
+        .collect();
+    // The buttons run the client's own commands.
+    let commands: Vec<String> = actions
+        .iter()
+        .filter_map(|action| {
+            let command = config.commands.command(action)?;
+            Some(format!(
+                "{}={}",
+                core::command_env(action),
+                shell_quote(&command)
+            ))
+        })
+        .collect();
+    if !config.agent.is_empty() {
+        return match agent::popup(config, default, &actions, blockers) {
+            Err(err) if agent::unreachable(&err) => {
+                warn!(
+                    "The agent on {} can't be reached, showing the popup over SSH: {}",
+                    config.name, err
+                );
+                show_popup(&without_agent(config), capabilities, action, blockers)
+            }
+            Ok(()) => {
+                info!("popup sent to the agent on {}", config.name);
+                Ok(())
+            }
+            Err(err) => Err(err),
+        };
+    }
+    let command: String = match config.client_shell {
+        true => format!(
+            "popup {} {} {} {}",
+            default,
+            config.default_delay,
+            // Quoted, so an empty list is still a word.
+            shell_quote(&actions.join(",")),
+            shell_quote(&blockers.join("; "))
+        ),
+        false => format!(
+        "export DISPLAY=:0 && export WAYLAND_DISPLAY=wayland-0 && MOD=gui DEFAULT={} SEC={} ACTIONS={} BLOCKERS={} {} {}",
+        default,
+        config.default_delay,
+        actions.join(","),
+        shell_quote(&blockers.join("; ")),
+        commands.join(" "),
+        core::GUI_APPNAME
+    ),
+    };
+    run_ssh(config, command)
+}
+
+// The client as if it had no agent, for going over SSH instead.
+fn without_agent(config: &ClientConfig) -> ClientConfig {
+    ClientConfig {
+        agent: String::new(),
+        ..config.clone()
+    }
+}
+
+// Whether someone is using the client: an active graphical session that
+// logind doesn't consider idle. When that can't be found out the popup is
+// shown, as it always was.
+fn someone_present(config: &ClientConfig) -> bool {
+    let query = match config.client_shell {
+        true => "status",
+        false => PRESENCE_QUERY,
+    };
+    match connect(config).and_then(|remote| remote.output(query)) {
+        Ok(output) => {
+            let presence = parse_presence(&output);
+            if !presence.someone_there() {
+                info!(
+                    "Nobody is using {} ({}), skipping the popup",
+                    config.name,
+                    presence.reason()
+                );
+            }
+            presence.someone_there()
+        }
+        Err(err) => {
+            warn!("Could not check whether {} is in use: {}", config.name, err);
+            true
+        }
+    }
+}
+
+// Type, state and idle hints of each session, as a "session" line followed
+// by Key=Value lines. The Manager's IdleHint is no use: it is only set when
+// every session is idle, and the SSH session asking never is.
+pub(crate) const PRESENCE_QUERY: &str =
+    "for session in $(loginctl list-sessions --no-legend | awk '{print $1}'); do \
+    echo session; loginctl show-session \"$session\" \
+    -p Type -p Active -p IdleHint -p IdleSinceHint; done";
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Presence {
+    graphical: bool,
+    idle: bool,
+    // Unix seconds, as the client's clock sees it.
+    idle_since: Option<u64>,
+}
+
+impl Presence {
+    fn someone_there(&self) -> bool {
+        self.graphical && !self.idle
+    }
+
+    fn reason(&self) -> String {
+        match (self.graphical, self.idle_since) {
+            (false, _) => "no active graphical session".to_string(),
+            (true, Some(since)) => format!(
+                "idle for {} minutes",
+                state::now().saturating_sub(since) / 60
+            ),
+            (true, None) => "idle".to_string(),
+        }
+    }
+}
+
+// Only active graphical sessions count; the client is idle when all of them
+// are, since the most recent of them went idle.
+fn parse_presence(output: &str) -> Presence {
+    let mut sessions: Vec<BTreeMap<&str, &str>> = Vec::new();
+    for line in output.lines().map(str::trim) {
+        match line.split_once('=') {
+            _ if line == "session" => sessions.push(BTreeMap::new()),
+            Some((key, value)) => {
+                if let Some(session) = sessions.last_mut() {
+                    session.insert(key, value);
+                }
+            }
+            None => {}
+        }
+    }
+
+    let active: Vec<&BTreeMap<&str, &str>> = sessions
+        .iter()
+        .filter(|session| {
+            ["x11", "wayland", "mir"].contains(&session.get("Type").copied().unwrap_or_default())
+                && session.get("Active") == Some(&"yes")
+        })
+        .collect();
+    let graphical = !active.is_empty();
+    let idle = graphical
+        && active
+            .iter()
+            .all(|session| session.get("IdleHint") == Some(&"yes"));
+    let idle_since = active
+        .iter()
+        .filter_map(|session| session.get("IdleSinceHint")?.parse::<u64>().ok())
+        .filter(|since| *since > 0)
+        .max()
+        .filter(|_| idle)
+        .map(|since| since / 1_000_000);
+
+    Presence {
+        graphical,
+        idle,
+        idle_since,
+    }
+}
+
+// Single quotes for sh, so any text can be passed as one word.
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+// What makes acting on the client unsafe right now: inhibitor locks that
+// block sleep or shutdown, and processes matching critical_processes. A
+// client that can't be asked has no blockers.
+pub(crate) fn blockers(config: &ClientConfig) -> Vec<String> {
+    if config.client_shell {
+        debug!(
+            "{} only allows client-shell, not checking for blockers",
+            config.name
+        );
+        return Vec::new();
+    }
+    let remote = match connect(config) {
+        Ok(remote) => remote,
+        Err(err) => {
+            debug!("Could not check {} for blockers: {}", config.name, err);
+            return Vec::new();
+        }
+    };
+
+    let mut blockers = match remote.output(INHIBITORS_QUERY) {
+        Ok(json) => parse_inhibitors(&json),
+        Err(err) => {
+            warn!("Could not list inhibitor locks on {}: {}", config.name, err);
+            Vec::new()
+        }
+    };
+    if !config.critical_processes.is_empty() {
+        match remote.output("ps -eo args=") {
+            Ok(processes) => blockers.extend(critical(&processes, &config.critical_processes)),
+            Err(err) => warn!("Could not list processes on {}: {}", config.name, err),
+        }
+    }
+    blockers
+}
+
+const INHIBITORS_QUERY: &str = "busctl --json=short call org.freedesktop.login1 \
+    /org/freedesktop/login1 org.freedesktop.login1.Manager ListInhibitors";
+
+// ListInhibitors returns a(ssssuu): what, who, why, mode, uid and pid. Only
+// "block" locks on sleep or shutdown matter, "delay" ones let go by themselves.
+fn parse_inhibitors(json: &str) -> Vec<String> {
+    let value: Value = serde_json::from_str(json).unwrap_or_default();
+    value["data"][0]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|lock| {
+            let field = |i: usize| lock[i].as_str().unwrap_or_default();
+            let blocks = field(0)
+                .split(':')
+                .any(|what| what == "sleep" || what == "shutdown");
+            (blocks && field(3) == "block").then(|| format!("{}: {}", field(1), field(2)))
+        })
+        .collect()
+}
+
+// Command lines containing one of the patterns.
+fn critical(processes: &str, patterns: &[String]) -> Vec<String> {
+    processes
+        .lines()
+        .map(str::trim)
+        .filter(|args| {
+            patterns
+                .iter()
+                .any(|pattern| args.contains(pattern.as_str()))
+        })
+        .map(ToString::to_string)
+        .collect()
+}
+
+// Each power state falls back to the next one when the client keeps
+// answering on its SSH port.
+const POWER_STATES: [&str; 3] = ["suspend", "hibernate", "poweroff"];
+
+// What logind on the client says it can do. Unknown clients are assumed to
+// support everything, as before capabilities were checked.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Capabilities {
+    pub(crate) suspend: Answer,
+    pub(crate) hibernate: Answer,
+    pub(crate) hybrid_sleep: Answer,
+    pub(crate) poweroff: Answer,
+    // Unix seconds of the query, 0 if it never succeeded.
+    #[serde(default)]
+    pub(crate) checked_at: u64,
+}
+
+// logind's answer to CanSuspend and friends, for the SSH user.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Answer {
+    Yes,
+    // Supported, but polkit wants a password from this user.
+    Challenge,
+    No,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            suspend: Answer::Yes,
+            hibernate: Answer::Yes,
+            hybrid_sleep: Answer::Yes,
+            poweroff: Answer::Yes,
+            checked_at: 0,
+        }
+    }
+}
+
+impl Capabilities {
+    fn answer(&self, action: &str) -> Answer {
+        match action {
+            "suspend" => self.suspend,
+            "hibernate" => self.hibernate,
+            "hybrid-sleep" => self.hybrid_sleep,
+            "poweroff" => self.poweroff,
+            _ => Answer::Yes,
+        }
+    }
+
+    pub(crate) fn supports(&self, action: &str) -> bool {
+        self.answer(action) != Answer::No
+    }
+
+    // The supported systemctl verbs, lightest first.
+    pub(crate) fn actions(&self) -> Vec<&'static str> {
+        ["suspend", "hybrid-sleep", "hibernate", "poweroff"]
+            .into_iter()
+            .filter(|action| self.supports(action))
+            .collect()
+    }
+}
+
+pub(crate) const LOGIND_QUERY: &str = "for method in CanSuspend CanHibernate CanHybridSleep CanPowerOff; do \
+    busctl call org.freedesktop.login1 /org/freedesktop/login1 org.freedesktop.login1.Manager $method; done";
+
+// Re-query once a day, swap or polkit rules may have changed.
+const CAPABILITIES_MAX_AGE: u64 = 24 * 60 * 60;
+
+impl Remote {
+    pub(crate) fn capabilities(&self) -> Result<Capabilities, Error> {
+        let query = match self.client_shell {
+            true => "capabilities",
+            false => LOGIND_QUERY,
+        };
+        parse_capabilities(&self.output(query)?)
+    }
+}
+
+// busctl prints one `s "yes"` per method. "challenge" means polkit would ask
+// for a password, which nobody can answer over SSH.
+fn parse_capabilities(output: &str) -> Result<Capabilities, Error> {
+    let answers: Vec<Answer> = output
+        .lines()
+        .map(|line| match line.trim() {
+            "s \"yes\"" => Answer::Yes,
+            "s \"challenge\"" => Answer::Challenge,
+            _ => Answer::No,
+        })
+        .collect();
+    let [suspend, hibernate, hybrid_sleep, poweroff] = answers[..] else {
+        return Err(Error::Command(format!(
+            "unexpected answer from logind: {}",
+            output
+        )));
+    };
+    Ok(Capabilities {
+        suspend,
+        hibernate,
+        hybrid_sleep,
+        poweroff,
+        checked_at: state::now(),
+    })
+}
+
+fn capabilities_path() -> PathBuf {
+    state::default_path().with_file_name("capabilities.json")
+}
+
+fn cached_capabilities() -> BTreeMap<String, Capabilities> {
+    fs::read_to_string(capabilities_path())
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+pub(crate) fn cache_capabilities(config: &ClientConfig, capabilities: &Capabilities) {
+    let mut cache = cached_capabilities();
+    cache.insert(config.name.clone(), capabilities.clone());
+    let path = capabilities_path();
+    let saved = fs::create_dir_all(path.parent().unwrap_or(Path::new("/")))
+        .and_then(|()| serde_json::to_string_pretty(&cache).map_err(io::Error::from))
+        .and_then(|data| config::write_atomic(&path, &data));
+    if let Err(err) = saved {
+        warn!(
+            "Could not cache capabilities in {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+// The cached capabilities, queried again over SSH when missing or stale.
+pub(crate) fn capabilities(config: &ClientConfig) -> Capabilities {
+    if let Some(cached) = cached_capabilities().remove(&config.name) {
+        if state::now().saturating_sub(cached.checked_at) < CAPABILITIES_MAX_AGE {
+            return cached;
+        }
+    }
+
+    match connect(config).and_then(|remote| remote.capabilities()) {
+        Ok(capabilities) => {
+            debug!("{} supports {:?}", config.name, capabilities.actions());
+            cache_capabilities(config, &capabilities);
+            capabilities
+        }
+        Err(err) => {
+            warn!(
+                "Could not ask logind on {} what it supports, assuming everything: {}",
+                config.name, err
+            );
+            Capabilities::default()
+        }
+    }
+}
+
+// Whether the client supports the power state, has a command for it and may
+// run it. Behind client-shell or the agent the client knows its commands and
+// may use sudo, as may our own commands with `sudo`, so a polkit challenge for
+// the SSH user doesn't matter there.
+pub(crate) fn available(config: &ClientConfig, capabilities: &Capabilities, action: &str) -> bool {
+    let own_commands = config.client_shell || !config.agent.is_empty();
+    let privileged = own_commands || config.commands.sudo;
+    let permitted = match capabilities.answer(action) {
+        Answer::Yes => true,
+        Answer::Challenge => privileged,
+        Answer::No => false,
+    };
+    permitted && (own_commands || config.commands.command(action).is_some())
+}
+
+// Requests the power state and waits for the client to go down, trying the
+// deeper states in turn. Returns the state that worked.
+fn enter_power_state(
+    config: &ClientConfig,
+    action: &str,
+    capabilities: &Capabilities,
+) -> Result<String, Error> {
+    let deadline = time::Duration::from_secs(config.power_deadline as u64);
+    fall_back(
+        config,
+        action,
+        capabilities,
+        |state| request_power_state(config, state),
+        || wait_for_status(config, false, deadline, time::Duration::from_secs(2)),
+    )
+}
+
+// The order enter_power_state goes in, with how a state is requested and how
+// the client is seen going down left to the caller.
+fn fall_back(
+    config: &ClientConfig,
+    action: &str,
+    capabilities: &Capabilities,
+    mut request: impl FnMut(&str) -> Result<(), Error>,
+    mut went_down: impl FnMut() -> bool,
+) -> Result<String, Error> {
+    let start = POWER_STATES
+        .iter()
+        .position(|state| *state == action)
+        .unwrap_or_default();
+    let mut failures = Vec::new();
+
+    for state in &POWER_STATES[start..] {
+        if !available(config, capabilities, state) {
+            debug!("{} can't {}, skipping it", config.name, state);
+            failures.push(format!("{}: not supported", state));
+            continue;
+        }
+        if let Err(err) = request(state) {
+            warn!("{} refused {}: {}", config.name, state, err);
+            failures.push(format!("{}: {}", state, err));
+            continue;
+        }
+        if went_down() {
+            return Ok(state.to_string());
+        }
+        warn!(
+            "{} still answers {}s after {}",
+            config.name, config.power_deadline, state
+        );
+        failures.push(format!(
+            "{}: still answering after {}s",
+            state, config.power_deadline
+        ));
+    }
+
+    Err(Error::Command(format!(
+        "{} did not power down ({})",
+        config.name,
+        failures.join("; ")
+    )))
+}
+
+fn request_power_state(config: &ClientConfig, state: &str) -> Result<(), Error> {
+    if !config.agent.is_empty() {
+        return match agent::request(config, state) {
+            Err(err) if agent::unreachable(&err) => {
+                warn!(
+                    "The agent on {} can't be reached, sending {} over SSH: {}",
+                    config.name, state, err
+                );
+                request_power_state(&without_agent(config), state)
+            }
+            result => result,
+        };
+    }
+    let command = match config.client_shell {
+        true => state.to_string(),
+        false => config.commands.command(state).ok_or_else(|| {
+            Error::Config(format!("{} has no command for {}", config.name, state))
+        })?,
+    };
+    let remote = connect(config)?;
+    // The client may go down before the command returns.
+    remote.session.set_timeout(10_000);
+    match remote.output(&command) {
+        Ok(_) => Ok(()),
+        Err(err @ (Error::Ssh(_) | Error::Io(_))) => {
+            debug!("connection to {} dropped: {}", config.name, err);
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// An authenticated SSH session with a client.
+pub(crate) struct Remote {
+    session: Session,
+    // Our own address on the connection, i.e. how the client sees us.
+    pub(crate) local_ip: IpAddr,
+    // Client name and SSH user, for log lines and error messages.
+    name: String,
+    user: String,
+    client_shell: bool,
+}
+
+// How long the client gets to accept the connection.
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+// How long any SSH call, the handshake included, may wait for the client, e.g.
+// one that stalls while going down. Callers expecting a longer command set
+// their own.
+const SESSION_TIMEOUT_MS: u32 = 30_000;
+
+pub(crate) fn connect(config: &ClientConfig) -> Result<Remote, Error> {
+    let address = config
+        .ip
+        .to_socket_addrs()
+        .map_err(Error::Network)?
+        .next()
+        .ok_or_else(|| {
+            Error::Network(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no address", config.ip),
+            ))
+        })?;
+    let tcp = TcpStreamSTD::connect_timeout(&address, CONNECT_TIMEOUT).map_err(Error::Network)?;
+    let local_ip = tcp.local_addr()?.ip();
+    let mut sess = Session::new()?;
+    sess.set_tcp_stream(tcp);
+    sess.set_timeout(SESSION_TIMEOUT_MS);
+    sess.handshake()?;
+    sess.userauth_password(&config.user, &secret::resolve(&config.key)?)
+        .map_err(|err| Error::Auth(format!("{}@{}: {}", config.user, config.ip, err)))?;
+
+    Ok(Remote {
+        session: sess,
+        local_ip,
+        name: config.name.clone(),
+        user: config.user.clone(),
+        client_shell: config.client_shell,
+    })
+}
+
+// What a remote command did. A non-zero code is not an error by itself, see
+// Remote::run.
+#[derive(Debug)]
+pub(crate) struct Output {
+    pub(crate) code: i32,
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+    pub(crate) duration: time::Duration,
+}
+
+impl Remote {
+    // Runs the command to completion, logging its output at debug level as it
+    // arrives. Honours the session timeout, if one is set.
+    pub(crate) fn exec(&self, command: &str) -> Result<Output, Error> {
+        let start = time::Instant::now();
+        let mut channel = self.session.channel_session()?;
+        channel.exec(command)?;
+        debug!("{}: running '{}'", self.name, command);
+
+        let timeout = match self.session.timeout() {
+            0 => None,
+            ms => Some(time::Duration::from_millis(ms as u64)),
+        };
+        let mut stdout = Lines::new(&self.name, "stdout");
+        let mut stderr = Lines::new(&self.name, "stderr");
+
+        // Both streams are read in turn so neither can fill up and stall the
+        // command; that needs a non-blocking session.
+        self.session.set_blocking(false);
+        let read = (|| loop {
+            let eof = channel.eof();
+            let count = stdout.read(&mut channel)? + stderr.read(&mut channel.stderr())?;
+            if eof && count == 0 {
+                return Ok(());
+            }
+            if count == 0 {
+                if timeout.is_some_and(|timeout| start.elapsed() > timeout) {
+                    return Err(Error::Ssh(ssh2::Error::new(
+                        ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT),
+                        "timed out waiting for the command",
+                    )));
+                }
+                thread::sleep(time::Duration::from_millis(20));
+            }
+        })();
+        self.session.set_blocking(true);
+        read?;
+
+        channel.wait_close()?;
+        let output = Output {
+            code: channel.exit_status()?,
+            stdout: stdout.finish(),
+            stderr: stderr.finish(),
+            duration: start.elapsed(),
+        };
+        debug!(
+            "{}: '{}' exited with {} after {:.1}s",
+            self.name,
+            command,
+            output.code,
+            output.duration.as_secs_f32()
+        );
+        Ok(output)
+    }
+
+    // Like exec, with a non-zero exit status turned into an error.
+    pub(crate) fn run(&self, command: &str) -> Result<Output, Error> {
+        let output = self.exec(command)?;
+        if output.code == 0 {
+            return Ok(output);
+        }
+
+        let stderr = output.stderr.trim();
+        if denied_by_polkit(stderr) {
+            return Err(Error::Permission(format!(
+                "{} may not run '{}' on {}: {}. Allow it with a polkit rule for the \
+                 org.freedesktop.login1 power actions or a passwordless sudo entry",
+                self.user, command, self.name, stderr
+            )));
+        }
+        Err(Error::Command(format!(
+            "'{}' on {} exited with {}: {}",
+            command, self.name, output.code, stderr
+        )))
+    }
+
+    // Runs a short command and returns its trimmed stdout. A non-zero exit
+    // status is an error.
+    pub(crate) fn output(&self, command: &str) -> Result<String, Error> {
+        Ok(self.run(command)?.stdout.trim().to_string())
+    }
+}
+
+// libssh2's LIBSSH2_ERROR_TIMEOUT.
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+
+// Only messages from logind, polkit and sudo -n; a plain "permission denied"
+// can be anything, e.g. a file the user may not read.
+fn denied_by_polkit(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    [
+        "interactive authentication required",
+        "org.freedesktop.login1",
+        "org.freedesktop.policykit1",
+        "sudo: a password is required",
+        "sudo: a terminal is required",
+    ]
+    .iter()
+    .any(|message| stderr.contains(message))
+}
+
+// Collects one output stream and logs it line by line.
+struct Lines<'a> {
+    name: &'a str,
+    stream: &'static str,
+    text: Vec<u8>,
+    logged: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn new(name: &'a str, stream: &'static str) -> Self {
+        Lines {
+            name,
+            stream,
+            text: Vec::new(),
+            logged: 0,
+        }
+    }
+
+    // Returns how many bytes were read, 0 when nothing is available yet.
+    fn read(&mut self, reader: &mut impl Read) -> Result<usize, Error> {
+        let mut buf = [0; 4096];
+        let count = match reader.read(&mut buf) {
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        self.text.extend_from_slice(&buf[..count]);
+
+        while let Some(end) = self.text[self.logged..].iter().position(|&b| b == b'\n') {
+            let line = String::from_utf8_lossy(&self.text[self.logged..self.logged + end]);
+            debug!("{} {}: {}", self.name, self.stream, line);
+            self.logged += end + 1;
+        }
+        Ok(count)
+    }
+
+    fn finish(self) -> String {
+        if self.logged < self.text.len() {
+            let line = String::from_utf8_lossy(&self.text[self.logged..]);
+            debug!("{} {}: {}", self.name, self.stream, line);
+        }
+        String::from_utf8_lossy(&self.text).into_owned()
+    }
+}
+
+// Starts the command and returns once it has run for a moment, the popup
+// keeps running until the user picks an action. A command that fails right
+// away is an error; a later failure is only logged.
+pub(crate) fn run_ssh(config: &ClientConfig, command: String) -> Result<(), Error> {
+    let remote = connect(config)?;
+    // The popup closes itself after default_delay seconds.
+    let popup = config.default_delay.saturating_add(60).saturating_mul(1000);
+    remote.session.set_timeout(popup.max(SESSION_TIMEOUT_MS));
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let result = remote.run(&command).map(|_| ());
+        if let Err(mpsc::SendError(Err(err))) = sender.send(result) {
+            error!("{}", err);
+        }
+    });
+
+    match receiver.recv_timeout(time::Duration::from_secs(2)) {
+        Ok(result) => result,
+        Err(_) => Ok(()),
+    }
+}
+
+// Polls the client until it reaches the wanted reachability or the deadline passes.
+pub(crate) fn wait_for_status(
+    config: &ClientConfig,
+    online: bool,
+    deadline: time::Duration,
+    interval: time::Duration,
+) -> bool {
+    let start = time::Instant::now();
+    loop {
+        if status(config) == online {
+            return true;
+        }
+        if start.elapsed() >= deadline {
+            return false;
+        }
+        thread::sleep(interval);
+    }
+}
+
+pub(crate) fn send_wol(config: &ClientConfig) -> Result<(), Error> {
+    let command = format!("wakeonlan {}", config.mac_address);
+
+    match core::run_command(&command) {
+        Ok(true) => {
+            info!("WOL command succeeded!");
+            Ok(())
+        }
+        Ok(false) => {
+            error!("WOL command failed!");
+            info!(
+                "Verify the mac address of the client and run '{} setup' to reconfiger to settings",
+                core::APPNAME
+            );
+            Err(Error::Command(format!("'{}' failed", command)))
+        }
+        Err(err) => {
+            error!("error sending wol {}", err);
+            info!(
+                "Verify the mac address of the client and run '{} setup' to reconfiger to settings",
+                core::APPNAME
+            );
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn collects_streamed_output() {
+        let mut lines = Lines::new("desktop", "stdout");
+        lines.read(&mut &b"first\nsec"[..]).unwrap();
+        lines.read(&mut &b"ond\nlast"[..]).unwrap();
+        assert_eq!(lines.logged, 13);
+        assert_eq!(lines.finish(), "first\nsecond\nlast");
+    }
+
+    #[test]
+    fn recognises_polkit_denials() {
+        assert!(denied_by_polkit(
+            "Failed to suspend system via logind: Interactive authentication required."
+        ));
+        assert!(denied_by_polkit(
+            "Call failed: Access denied (org.freedesktop.login1.power-off)"
+        ));
+        assert!(denied_by_polkit("sudo: a password is required"));
+        assert!(!denied_by_polkit("Unit hibernate.target not found."));
+        assert!(!denied_by_polkit(
+            "sh: 1: cannot create /sys/power/state: Permission denied"
+        ));
+        assert!(!denied_by_polkit("Permission denied (publickey,password)."));
+    }
+
+    #[test]
+    fn finds_blockers() {
+        let json = r#"{"type":"a(ssssuu)","data":[[
+            ["handle-power-key:handle-suspend-key","NetworkManager","","block",0,812],
+            ["sleep","NetworkManager","NetworkManager needs to turn off networks","delay",0,812],
+            ["shutdown:sleep","restic","Backup in progress","block",1000,4242]
+        ]]}"#;
+        assert_eq!(parse_inhibitors(json), ["restic: Backup in progress"]);
+        assert!(parse_inhibitors("").is_empty());
+
+        let processes = "/usr/bin/pacman -Syu\nbash\n/usr/lib/firefox/firefox\n";
+        let patterns = ["pacman".to_string(), "apt-get".to_string()];
+        assert_eq!(critical(processes, &patterns), ["/usr/bin/pacman -Syu"]);
+    }
+
+    #[test]
+    fn tells_whether_someone_is_there() {
+        let session = |kind: &str, active: &str, idle: &str, since: u64| {
+            format!(
+                "session\nType={}\nActive={}\nIdleHint={}\nIdleSinceHint={}\n",
+                kind, active, idle, since
+            )
+        };
+        // The SSH session upsync asks from is never idle.
+        let ssh = session("tty", "yes", "no", 0);
+
+        let active = parse_presence(&(ssh.clone() + &session("wayland", "yes", "no", 0)));
+        assert!(active.someone_there());
+
+        let idle = parse_presence(
+            &(ssh.clone()
+                + &session("x11", "yes", "yes", 1_700_000_000_000_000)
+                + &session("x11", "no", "no", 0)),
+        );
+        assert!(!idle.someone_there());
+        assert_eq!(idle.idle_since, Some(1_700_000_000));
+
+        let headless = parse_presence(&ssh);
+        assert!(!headless.someone_there());
+        assert_eq!(headless.reason(), "no active graphical session");
+    }
+
+    #[test]
+    fn falls_back_to_ssh_without_the_agent() {
+        // Nothing listens where the agent should be.
+        let agent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let agent_address = agent.local_addr().unwrap().to_string();
+        drop(agent);
+        let ssh = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ClientConfig {
+            name: "desktop".to_string(),
+            ip: ssh.local_addr().unwrap().to_string(),
+            key: "plain:hunter2".to_string(),
+            agent: agent_address,
+            agent_key: format!("plain:{}", "ab".repeat(32)),
+            ..ClientConfig::default()
+        };
+        let server = thread::spawn(move || ssh.accept().is_ok());
+
+        // Our SSH "server" hangs up right away, but it was asked.
+        let result = request_power_state(&config, "suspend");
+        assert!(server.join().unwrap());
+        assert!(matches!(result, Err(Error::Ssh(_))));
+    }
+
+    #[test]
+    fn falls_back_to_deeper_states() {
+        let config = ClientConfig {
+            name: "desktop".to_string(),
+            ..ClientConfig::default()
+        };
+        let capabilities = Capabilities {
+            hibernate: Answer::No,
+            ..Capabilities::default()
+        };
+
+        // suspend is ignored, hibernate unsupported, poweroff works.
+        let mut requested = Vec::new();
+        let mut answers = [false, true].into_iter();
+        let state = fall_back(
+            &config,
+            "suspend",
+            &capabilities,
+            |state| {
+                requested.push(state.to_string());
+                Ok(())
+            },
+            || answers.next().unwrap(),
+        );
+        assert_eq!(state.unwrap(), "poweroff");
+        assert_eq!(requested, ["suspend", "poweroff"]);
+
+        // Starts at the configured state and reports every failure.
+        let mut requested = Vec::new();
+        let result = fall_back(
+            &config,
+            "hibernate",
+            &capabilities,
+            |state| {
+                requested.push(state.to_string());
+                Err(Error::Command("refused".to_string()))
+            },
+            || true,
+        );
+        assert_eq!(requested, ["poweroff"]);
+        assert!(matches!(
+            result,
+            Err(Error::Command(message)) if message == "desktop did not power down \
+                (hibernate: not supported; poweroff: command failed: refused)"
+        ));
+    }
+
+    #[test]
+    fn reads_logind_capabilities() {
+        let capabilities =
+            parse_capabilities("s \"yes\"\ns \"na\"\ns \"challenge\"\ns \"yes\"\n").unwrap();
+        assert_eq!(
+            capabilities.actions(),
+            ["suspend", "hybrid-sleep", "poweroff"]
+        );
+        assert!(!capabilities.supports("hibernate"));
+        assert!(parse_capabilities("Failed to connect to bus").is_err());
+
+        // hybrid-sleep needs a password, so only sudo can run it.
+        let mut config = ClientConfig::default();
+        assert!(available(&config, &capabilities, "suspend"));
+        assert!(!available(&config, &capabilities, "hybrid-sleep"));
+        config.commands.sudo = true;
+        assert!(available(&config, &capabilities, "hybrid-sleep"));
+        assert!(!available(&config, &capabilities, "hibernate"));
+    }
+}
@@ -0,0 +1,277 @@
+use crate::client::{LOGIND_QUERY, PRESENCE_QUERY};
+use crate::core::{self, Commands, Preset};
+use crate::Error;
+use log::{info, warn};
+use serde_json::Value;
+use std::{env, process};
+
+// `upsync client-shell` is meant to be the only thing the server may run on a
+// client, e.g. in ~/.ssh/authorized_keys:
+//
+//   command="upsync client-shell --preset openrc --sudo" ssh-ed25519 AAAA...
+//
+// or with `ForceCommand` in a `Match User` block of sshd_config for password
+// logins. The requested command is in SSH_ORIGINAL_COMMAND; only these verbs
+// are allowed:
+//
+//   popup <default> <seconds> <actions> [blockers]
+//   suspend | hibernate | hybrid-sleep | poweroff
+//   status
+//   capabilities
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    Popup {
+        default: String,
+        seconds: u32,
+        actions: Vec<String>,
+        blockers: String,
+    },
+    Power(String),
+    Status,
+    Capabilities,
+}
+
+const POWER_VERBS: [&str; 4] = ["suspend", "hibernate", "hybrid-sleep", "poweroff"];
+const QUERY_VERBS: [&str; 2] = ["status", "capabilities"];
+const POPUP_USAGE: &str = "usage: popup <default> <seconds> <actions> [blockers]";
+
+// Nobody waits longer than an hour for a popup during an outage. Config
+// validation holds default_delay to the same limit.
+pub(crate) const MAX_POPUP_SECONDS: u32 = 60 * 60;
+
+pub(crate) fn run(args: &[String]) -> Result<(), Error> {
+    let commands = commands(args)?;
+    let original = env::var("SSH_ORIGINAL_COMMAND").unwrap_or_default();
+
+    let request = match parse(&original) {
+        Ok(request) => request,
+        Err(reason) => {
+            audit("warning", &format!("rejected '{}': {}", original, reason));
+            return Err(Error::Usage(format!(
+                "{} client-shell refused '{}': {}",
+                core::APPNAME,
+                original,
+                reason
+            )));
+        }
+    };
+    audit("info", &format!("running '{}'", original));
+
+    match request {
+        Request::Popup {
+            default,
+            seconds,
+            actions,
+            blockers,
+        } => {
+            let mut gui = process::Command::new(core::GUI_APPNAME);
+            gui.env("DISPLAY", ":0")
+                .env("WAYLAND_DISPLAY", "wayland-0")
+                .env("MOD", "gui")
+                .env("DEFAULT", &default)
+                .env("SEC", seconds.to_string())
+                .env("ACTIONS", actions.join(","))
+                .env("BLOCKERS", &blockers);
+            for action in &actions {
+                if let Some(command) = commands.command(action) {
+                    gui.env(core::command_env(action), command);
+                }
+            }
+            check(gui.status()?, core::GUI_APPNAME)
+        }
+        Request::Power(action) => {
+            let command = commands
+                .command(&action)
+                .ok_or_else(|| Error::Usage(format!("no command for {}", action)))?;
+            shell(&command)
+        }
+        Request::Status => shell(PRESENCE_QUERY),
+        Request::Capabilities => shell(LOGIND_QUERY),
+    }
+}
+
+// The power commands come from the forced command line, never from the
+// server: --preset <name> and --sudo. The agent takes the same flags.
+pub(crate) fn commands(args: &[String]) -> Result<Commands, Error> {
+    let preset = match core::get_flag(args, "--preset") {
+        Some(name) => serde_json::from_value::<Preset>(Value::String(name.to_string()))
+            .map_err(|_| Error::Usage(format!("Unknown preset: {}", name)))?,
+        None => Preset::default(),
+    };
+    Ok(Commands {
+        preset,
+        sudo: args.iter().any(|arg| arg == "--sudo"),
+        ..Commands::default()
+    })
+}
+
+fn parse(command: &str) -> Result<Request, String> {
+    if command.trim().is_empty() {
+        return Err("interactive logins are not allowed".to_string());
+    }
+    let words = split(command)?;
+    let args: Vec<&str> = words.iter().map(String::as_str).collect();
+
+    match args[..] {
+        ["popup", default, seconds, actions] => popup(default, seconds, actions, ""),
+        ["popup", default, seconds, actions, blockers] => {
+            popup(default, seconds, actions, blockers)
+        }
+        ["popup", ..] => Err(POPUP_USAGE.to_string()),
+        [verb] if POWER_VERBS.contains(&verb) => Ok(Request::Power(verb.to_string())),
+        ["status"] => Ok(Request::Status),
+        ["capabilities"] => Ok(Request::Capabilities),
+        [verb, ..] if POWER_VERBS.contains(&verb) || QUERY_VERBS.contains(&verb) => {
+            Err(format!("{} takes no arguments", verb))
+        }
+        [verb, ..] => Err(format!("'{}' is not allowed", verb)),
+        [] => Err("empty command".to_string()),
+    }
+}
+
+fn popup(default: &str, seconds: &str, actions: &str, blockers: &str) -> Result<Request, String> {
+    if !POWER_VERBS.contains(&default) {
+        return Err(format!("unknown default action '{}'", default));
+    }
+    let seconds: u32 = seconds
+        .parse()
+        .ok()
+        .filter(|seconds| (1..=MAX_POPUP_SECONDS).contains(seconds))
+        .ok_or_else(|| format!("seconds must be 1 to {}", MAX_POPUP_SECONDS))?;
+    // The client may support none of them; the popup then only counts down.
+    let actions: Vec<String> = match actions {
+        "" => Vec::new(),
+        actions => actions.split(',').map(str::to_string).collect(),
+    };
+    if let Some(action) = actions.iter().find(|a| !POWER_VERBS.contains(&a.as_str())) {
+        return Err(format!("unknown action '{}'", action));
+    }
+    if blockers.len() > 1000 || blockers.chars().any(char::is_control) {
+        return Err("blockers must be a single line of at most 1000 bytes".to_string());
+    }
+    Ok(Request::Popup {
+        default: default.to_string(),
+        seconds,
+        actions,
+        blockers: blockers.to_string(),
+    })
+}
+
+// Splits the command into words the way sh would for the quoting the server
+// uses: single quotes and backslash escapes. Anything else sh would act on is
+// refused rather than interpreted.
+fn split(command: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => words.extend(word.take()),
+            '\'' => {
+                let quoted = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => quoted.push(c),
+                        None => return Err("unterminated quote".to_string()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("trailing backslash".to_string()),
+            },
+            c if c.is_alphanumeric() || "-_,.:/=@+%".contains(c) => {
+                word.get_or_insert_with(String::new).push(c)
+            }
+            c => {
+                return Err(format!(
+                    "'{}' is not allowed outside quotes",
+                    c.escape_default()
+                ))
+            }
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn shell(command: &str) -> Result<(), Error> {
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()?;
+    check(status, command)
+}
+
+fn check(status: process::ExitStatus, command: &str) -> Result<(), Error> {
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::Command(format!(
+            "'{}' exited with {}",
+            command, status
+        ))),
+    }
+}
+
+// Everything the server asks for goes to the system log (auth facility), with
+// the address it came from.
+fn audit(priority: &str, message: &str) {
+    let from = env::var("SSH_CONNECTION").unwrap_or_default();
+    let from = from.split_whitespace().next().unwrap_or("unknown");
+    let message = format!("{} from {}", message, from);
+    match priority {
+        "info" => info!("{}", message),
+        _ => warn!("{}", message),
+    }
+
+    let logged = process::Command::new("logger")
+        .args(["-t", "upsync-client-shell", "-p"])
+        .arg(format!("auth.{}", priority))
+        .arg(&message)
+        .status();
+    if !matches!(logged, Ok(status) if status.success()) {
+        warn!("Could not write to the system log");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allows_only_known_verbs() {
+        assert_eq!(parse("suspend"), Ok(Request::Power("suspend".to_string())));
+        assert_eq!(parse("status"), Ok(Request::Status));
+        assert_eq!(
+            parse("popup hibernate 30 suspend,hibernate 'restic: it'\\''s running'"),
+            Ok(Request::Popup {
+                default: "hibernate".to_string(),
+                seconds: 30,
+                actions: vec!["suspend".to_string(), "hibernate".to_string()],
+                blockers: "restic: it's running".to_string(),
+            })
+        );
+
+        assert_eq!(
+            parse("popup poweroff 3600 '' 'restic'"),
+            Ok(Request::Popup {
+                default: "poweroff".to_string(),
+                seconds: 3600,
+                actions: Vec::new(),
+                blockers: "restic".to_string(),
+            })
+        );
+
+        assert!(parse("").is_err());
+        assert!(parse("reboot").is_err());
+        assert!(parse("suspend; rm -rf ~").is_err());
+        assert!(parse("poweroff now").is_err());
+        assert!(parse("popup suspend $(id) suspend").is_err());
+        assert!(parse("popup suspend 0 suspend").is_err());
+        assert!(parse("popup suspend 30 suspend,reboot").is_err());
+        assert!(parse("popup suspend 30 suspend,,hibernate").is_err());
+        assert!(parse("popup suspend 3601 suspend").is_err());
+        assert!(parse("popup suspend 30 suspend 'line\nbreak'").is_err());
+    }
+}
@@ -0,0 +1,646 @@
+mod layers;
+
+pub(crate) use layers::{coerce, load_layered_with, samples};
+pub use layers::{load_layered, Layered};
+
+use crate::core::{self, ClientConfig};
+use crate::monitor::{self, WakePolicy};
+use crate::secret::Secret;
+use crate::{client_shell, protocol, Error};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+// Bump this and append to MIGRATIONS whenever the file layout changes in a way
+// serde defaults can't cover.
+pub const CONFIG_VERSION: u64 = 2;
+
+type Migration = fn(Value) -> Result<Value, Error>;
+
+// MIGRATIONS[n] upgrades a version n + 1 file to version n + 2.
+const MIGRATIONS: &[Migration] = &[v1_to_v2];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+    pub version: u64,
+    #[serde(default = "default_delay_between_tasks")]
+    pub delay_between_tasks: u64,
+    // Which clients are woken when power returns: "slept", "running" or
+    // "always".
+    #[serde(default)]
+    pub wake_policy: WakePolicy,
+    // Seconds between two WOL packets when power returns.
+    #[serde(default = "default_wake_stagger")]
+    pub wake_stagger: u64,
+    // Seconds to wait for a client that has to go down before the next ones.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    // Seconds into an outage that an action may be held back by inhibitor
+    // locks or critical processes; keep it well below the UPS runtime.
+    #[serde(default = "default_defer_limit")]
+    pub defer_limit: u64,
+    // Run through `sh -c` when something needs attention, with the event,
+    // the client and a message as $1, $2 and $3. Empty to only log.
+    #[serde(default)]
+    pub notify_command: String,
+    pub clients: Vec<ClientConfig>,
+}
+
+fn default_delay_between_tasks() -> u64 {
+    5
+}
+
+fn default_wake_stagger() -> u64 {
+    10
+}
+
+fn default_shutdown_timeout() -> u64 {
+    180
+}
+
+fn default_defer_limit() -> u64 {
+    300
+}
+
+impl Config {
+    pub fn new(clients: Vec<ClientConfig>) -> Config {
+        Config {
+            version: CONFIG_VERSION,
+            delay_between_tasks: default_delay_between_tasks(),
+            wake_policy: WakePolicy::default(),
+            wake_stagger: default_wake_stagger(),
+            shutdown_timeout: default_shutdown_timeout(),
+            defer_limit: default_defer_limit(),
+            notify_command: String::new(),
+            clients,
+        }
+    }
+
+    pub fn client(&self, name: &str) -> Option<&ClientConfig> {
+        self.clients
+            .iter()
+            .find(|client| client.name == name || client.ip == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+}
+
+impl Format {
+    // Anything not ending in .toml is treated as JSON.
+    pub fn of(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            _ => Format::Json,
+        }
+    }
+}
+
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/upsync";
+
+// The per-user config file that setup writes: $XDG_CONFIG_HOME/upsync, with
+// config.toml for new installs and an existing config.json kept as is.
+pub fn config_path() -> PathBuf {
+    let dir = user_config_dir();
+    find_in(&dir).unwrap_or_else(|| dir.join("config.toml"))
+}
+
+pub fn user_config_dir() -> PathBuf {
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join(core::APPNAME),
+        _ => home_dir().join(".config").join(core::APPNAME),
+    }
+}
+
+// Where versions before layered config kept their file.
+pub fn legacy_config_dir() -> PathBuf {
+    home_dir().join(".local/share/upsync")
+}
+
+pub(crate) fn home_dir() -> PathBuf {
+    PathBuf::from(env::var("HOME").unwrap_or_else(|_| "/tmp".to_string()))
+}
+
+// config.toml wins over config.json when both exist in the same directory.
+fn find_in(dir: &Path) -> Option<PathBuf> {
+    ["config.toml", "config.json"]
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.exists())
+}
+
+// Loads and validates a single config file, upgrading it in place first if it
+// was written by an older version.
+pub fn load(path: &Path) -> Result<Config, Error> {
+    let config = parse(upgrade(path)?)?;
+    match validate(&config).as_slice() {
+        [] => Ok(config),
+        problems => Err(invalid(&path.display().to_string(), problems)),
+    }
+}
+
+fn invalid(source: &str, problems: &[Problem]) -> Error {
+    Error::Config(format!(
+        "{} has {} problem(s):\n{}",
+        source,
+        problems.len(),
+        problems
+            .iter()
+            .map(Problem::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))
+}
+
+// Reads and migrates a file. Files written by an older version are rewritten
+// in the current layout and the original is kept next to them as
+// config.<ext>.v<N>.bak. A read-only file (e.g. in /etc) is only migrated in
+// memory.
+fn upgrade(path: &Path) -> Result<Value, Error> {
+    let (value, version) = read(path)?;
+    if version >= CONFIG_VERSION {
+        return Ok(value);
+    }
+
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", version));
+    let encoded = encode(Format::of(path), &value)?;
+    let written = fs::copy(path, &backup).and_then(|_| write_atomic(path, &encoded));
+    match written {
+        Ok(()) => info!(
+            "Upgraded config from version {} to {} (backup: {})",
+            version,
+            CONFIG_VERSION,
+            PathBuf::from(backup).display()
+        ),
+        Err(err) => warn!(
+            "Could not upgrade {} in place, using it as is: {}",
+            path.display(),
+            err
+        ),
+    }
+    Ok(value)
+}
+
+// Like load, but never writes to disk and returns the problems instead of
+// failing on them. Used by `upsync config check`.
+pub fn check(path: &Path) -> Result<Vec<Problem>, Error> {
+    let (value, _) = read(path)?;
+    Ok(validate(&parse(value)?))
+}
+
+pub fn save(path: &Path, config: &Config) -> Result<(), Error> {
+    let value = serde_json::to_value(config)
+        .map_err(|err| Error::Config(format!("Failed to serialize config: {}", err)))?;
+    save_document(path, &value)
+}
+
+pub(crate) fn save_document(path: &Path, value: &Value) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(path, &encode(Format::of(path), value)?)?;
+    Ok(())
+}
+
+// The file holds the SSH password, so it is only readable by its owner. It is
+// written next to the target and renamed over it so a crash never leaves a
+// half written config behind.
+pub(crate) fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+// The migrated content of a single file, or an empty document for a file
+// that doesn't exist yet.
+pub(crate) fn read_document(path: &Path) -> Result<Value, Error> {
+    match path.exists() {
+        true => Ok(read(path)?.0),
+        false => Ok(json!({ "version": CONFIG_VERSION })),
+    }
+}
+
+// Returns the migrated document together with the version it was stored as.
+fn read(path: &Path) -> Result<(Value, u64), Error> {
+    debug!("{}", path.display());
+    let data = fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Failed to read {}: {}", path.display(), e)))?;
+    let value: Value = match Format::of(path) {
+        Format::Json => serde_json::from_str(&data)
+            .map_err(|e| Error::Config(format!("Failed to parse JSON: {}", e)))?,
+        Format::Toml => toml::from_str(&data)
+            .map_err(|e| Error::Config(format!("Failed to parse TOML: {}", e)))?,
+    };
+    let version = file_version(&value);
+    Ok((migrate(value)?, version))
+}
+
+fn parse(value: Value) -> Result<Config, Error> {
+    serde_json::from_value(value).map_err(|e| Error::Config(format!("Invalid config: {}", e)))
+}
+
+fn encode(format: Format, value: &Value) -> Result<String, Error> {
+    match format {
+        Format::Json => serde_json::to_string_pretty(value)
+            .map_err(|e| Error::Config(format!("Failed to write JSON: {}", e))),
+        Format::Toml => toml::to_string_pretty(value)
+            .map_err(|e| Error::Config(format!("Failed to write TOML: {}", e))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    // Dotted path of the offending field, e.g. "clients.0.mac_address".
+    pub path: String,
+    pub message: String,
+    pub hint: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} (hint: {})", self.path, self.message, self.hint)
+    }
+}
+
+// Reports every problem at once instead of stopping at the first one.
+pub fn validate(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |path: String, message: &str, hint: &str| {
+        problems.push(Problem {
+            path,
+            message: message.to_string(),
+            hint: hint.to_string(),
+        })
+    };
+
+    if config.delay_between_tasks == 0 {
+        problem(
+            "delay_between_tasks".to_string(),
+            "must be greater than zero",
+            "5 seconds is a good default",
+        );
+    }
+    if config.clients.is_empty() {
+        problem(
+            "clients".to_string(),
+            "no clients configured",
+            &format!("run '{} setup' to add one", core::APPNAME),
+        );
+    }
+
+    for (i, client) in config.clients.iter().enumerate() {
+        let field = |name: &str| format!("clients.{}.{}", i, name);
+
+        if client.name.trim().is_empty() {
+            problem(
+                field("name"),
+                "must not be empty",
+                "use the hostname of the client",
+            );
+        } else if config.clients[..i]
+            .iter()
+            .any(|other| other.name == client.name)
+        {
+            problem(
+                field("name"),
+                "is used by another client",
+                "client names must be unique",
+            );
+        }
+        if client.user.is_empty() {
+            problem(
+                field("user"),
+                "must not be empty",
+                "the SSH user on the client",
+            );
+        }
+        if client.key.is_empty() {
+            problem(
+                field("key"),
+                "must not be empty",
+                "the SSH password of the user",
+            );
+        } else if let Err(err) = Secret::parse(&client.key) {
+            problem(
+                field("key"),
+                &err.to_string(),
+                "use file:, env:, credential:, keyring:, age: or plain: followed by a value",
+            );
+        }
+        if !valid_address(&client.ip) {
+            problem(
+                field("ip"),
+                &format!("'{}' is not a valid address", client.ip),
+                "use host:port, e.g. 192.168.66.99:22",
+            );
+        }
+        if client.wake && !valid_mac(&client.mac_address) {
+            problem(
+                field("mac_address"),
+                &format!("'{}' is not a valid MAC address", client.mac_address),
+                "use six hex pairs, e.g. aa:bb:cc:dd:ee:ff, or disable wake",
+            );
+        }
+        if !(1..=client_shell::MAX_POPUP_SECONDS).contains(&client.default_delay) {
+            problem(
+                field("default_delay"),
+                &format!(
+                    "must be between 1 and {} seconds",
+                    client_shell::MAX_POPUP_SECONDS
+                ),
+                "30 seconds is a good default",
+            );
+        }
+        if client.power_deadline == 0 {
+            problem(
+                field("power_deadline"),
+                "must be greater than zero",
+                "how long the client takes to suspend, e.g. 60 seconds",
+            );
+        }
+        if client.wake && client.wake_timeout == 0 {
+            problem(
+                field("wake_timeout"),
+                "must be greater than zero",
+                "how long the client takes to boot, e.g. 120 seconds",
+            );
+        }
+        if !client.agent.is_empty() {
+            if !valid_address(&client.agent) {
+                problem(
+                    field("agent"),
+                    &format!("'{}' is not a valid address", client.agent),
+                    "use host:port, e.g. 192.168.66.99:7654",
+                );
+            }
+            if client.agent_key.is_empty() {
+                problem(
+                    field("agent_key"),
+                    "must not be empty when agent is set",
+                    "the key the agent was started with, e.g. file:/path",
+                );
+            } else {
+                match Secret::parse(&client.agent_key) {
+                    Err(err) => problem(
+                        field("agent_key"),
+                        &err.to_string(),
+                        "use file:, env:, credential:, keyring:, age: or plain: followed by a value",
+                    ),
+                    Ok(Secret::Plain(key)) => {
+                        if let Err(err) = protocol::parse_key(key) {
+                            problem(
+                                field("agent_key"),
+                                &err.to_string(),
+                                "better keep it in a file: openssl rand -hex 32 > agent.key",
+                            )
+                        }
+                    }
+                    Ok(_) => {}
+                }
+            }
+        }
+        if client.client_shell && !client.post_wake_command.is_empty() {
+            problem(
+                field("post_wake_command"),
+                "can't run behind client-shell",
+                "remove it or turn client_shell off",
+            );
+        }
+        let dependencies = [
+            ("wake_after", &client.wake_after),
+            ("shutdown_after", &client.shutdown_after),
+        ];
+        for (name, list) in dependencies {
+            for dependency in list {
+                let known = config.clients.iter().any(|other| &other.name == dependency);
+                if dependency == &client.name || !known {
+                    problem(
+                        field(name),
+                        &format!("'{}' is not another configured client", dependency),
+                        "list the names of the clients to wait for",
+                    );
+                }
+            }
+        }
+    }
+    let orders = [
+        ("wake_after", monitor::wake_order(&config.clients)),
+        ("shutdown_after", monitor::shutdown_order(&config.clients)),
+    ];
+    for (name, order) in orders {
+        if let Err(err) = order {
+            problem(
+                "clients".to_string(),
+                &err,
+                &format!("remove one of the {} entries", name),
+            );
+        }
+    }
+
+    problems
+}
+
+fn valid_address(ip: &str) -> bool {
+    let Some((host, port)) = ip.rsplit_once(':') else {
+        return false;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    // Digits and dots only is meant as an IPv4 address, never a hostname.
+    let numeric = host.chars().all(|c| c.is_ascii_digit() || c == '.');
+    let valid_host = host.parse::<IpAddr>().is_ok()
+        || (!host.is_empty()
+            && !numeric
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'));
+    valid_host && matches!(port.parse::<u16>(), Ok(port) if port > 0)
+}
+
+fn valid_mac(mac: &str) -> bool {
+    let parts: Vec<&str> = mac.split([':', '-']).collect();
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// Files without a version field predate versioning and are version 1, unless
+// they are a partial layer that doesn't use the flat version 1 layout.
+fn file_version(value: &Value) -> u64 {
+    match value.get("version").and_then(Value::as_u64) {
+        Some(version) => version,
+        None if value.get("ip").is_some() && value.get("clients").is_none() => 1,
+        None => CONFIG_VERSION,
+    }
+}
+
+pub fn migrate(mut value: Value) -> Result<Value, Error> {
+    let mut version = file_version(&value);
+    if version == 0 {
+        return Err(Error::Config("config version 0 does not exist".to_string()));
+    }
+    if version > CONFIG_VERSION {
+        return Err(Error::Config(format!(
+            "config version {} is newer than this {} supports ({})",
+            version,
+            core::APPNAME,
+            CONFIG_VERSION
+        )));
+    }
+
+    while version < CONFIG_VERSION {
+        value = MIGRATIONS[version as usize - 1](value)?;
+        version += 1;
+        value["version"] = json!(version);
+    }
+    Ok(value)
+}
+
+// Version 1 was a single flat client. Version 2 moves it into a named client
+// list and keeps the polling delay at the top level.
+fn v1_to_v2(mut value: Value) -> Result<Value, Error> {
+    let client = value
+        .as_object_mut()
+        .ok_or_else(|| Error::Config("expected a JSON object".to_string()))?;
+
+    let delay = client
+        .remove("delay_between_tasks")
+        .unwrap_or_else(|| json!(default_delay_between_tasks()));
+    let name = client
+        .get("ip")
+        .and_then(Value::as_str)
+        .map(core::host_of)
+        .unwrap_or("default")
+        .to_string();
+    client.insert("name".to_string(), json!(name));
+
+    Ok(json!({
+        "version": 2,
+        "delay_between_tasks": delay,
+        "clients": [value],
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn migrates_flat_v1_config() {
+        let v1 = json!({
+            "user": "dhanu",
+            "key": "secret",
+            "ip": "192.168.1.240:22",
+            "wake": true,
+            "mac_address": "aa:bb:cc:dd:ee:ff",
+            "default_behaviour": "Sleep",
+            "default_delay": 30,
+            "delay_between_tasks": 7,
+            "popup": true
+        });
+
+        let config: Config = serde_json::from_value(migrate(v1).unwrap()).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.delay_between_tasks, 7);
+        assert_eq!(config.clients.len(), 1);
+        assert_eq!(config.clients[0].name, "192.168.1.240");
+        assert_eq!(config.clients[0].user, "dhanu");
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut config: Config = serde_json::from_value(json!({
+            "version": CONFIG_VERSION,
+            "delay_between_tasks": 0,
+            "clients": [{
+                "name": "desktop",
+                "user": "dhanu",
+                "key": "secret",
+                "ip": "192.168.1.300",
+                "wake": true,
+                "mac_address": "aa:bb:cc",
+                "default_delay": 0
+            }]
+        }))
+        .unwrap();
+
+        let paths: Vec<String> = validate(&config).into_iter().map(|p| p.path).collect();
+        assert_eq!(
+            paths,
+            [
+                "delay_between_tasks",
+                "clients.0.ip",
+                "clients.0.mac_address",
+                "clients.0.default_delay"
+            ]
+        );
+
+        config.delay_between_tasks = 5;
+        config.clients[0].ip = "192.168.1.300:22".to_string();
+        config.clients[0].mac_address = "AA-BB-CC-DD-EE-FF".to_string();
+        config.clients[0].default_delay = 7200;
+        let paths: Vec<String> = validate(&config).into_iter().map(|p| p.path).collect();
+        assert_eq!(paths, ["clients.0.ip", "clients.0.default_delay"]);
+
+        config.clients[0].default_delay = 30;
+        let paths: Vec<String> = validate(&config).into_iter().map(|p| p.path).collect();
+        assert_eq!(paths, ["clients.0.ip"]);
+
+        config.clients[0].ip = "192.168.1.30:22".to_string();
+        assert!(validate(&config).is_empty());
+    }
+
+    #[test]
+    fn round_trips_toml() {
+        let config: Config = serde_json::from_value(json!({
+            "version": CONFIG_VERSION,
+            "clients": [{ "name": "desktop", "user": "dhanu", "key": "secret", "ip": "10.0.0.2:22" }]
+        }))
+        .unwrap();
+        let value = serde_json::to_value(&config).unwrap();
+        let text = encode(Format::Toml, &value).unwrap();
+        let back: Value = toml::from_str(&text).unwrap();
+        assert_eq!(back, value);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert!(migrate(json!({ "version": CONFIG_VERSION + 1 })).is_err());
+        assert!(matches!(
+            migrate(json!({ "version": 0 })),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn fills_optional_fields() {
+        let config: Config = serde_json::from_value(json!({
+            "version": CONFIG_VERSION,
+            "clients": [{ "name": "desktop", "user": "dhanu", "key": "secret", "ip": "10.0.0.2:22" }]
+        }))
+        .unwrap();
+        assert_eq!(config.delay_between_tasks, 5);
+        assert!(config.clients[0].popup);
+        assert!(!config.clients[0].wake);
+    }
+}
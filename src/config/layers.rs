@@ -0,0 +1,329 @@
+use super::{find_in, invalid, parse, upgrade, validate, Config};
+use crate::core::ClientConfig;
+use crate::Error;
+use log::{debug, warn};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+pub const ENV_PREFIX: &str = "UPSYNC_";
+
+// The effective config together with where each value came from.
+pub struct Layered {
+    pub config: Config,
+    // Files that were found, lowest priority first.
+    pub files: Vec<PathBuf>,
+    // Dotted path ("delay_between_tasks", "clients.desktop.user") to the file
+    // or environment variable that set it. Paths missing here are defaults.
+    pub origins: BTreeMap<String, String>,
+    merged: Value,
+}
+
+impl Layered {
+    // Every effective value with its path and origin, in file order.
+    pub fn values(&self) -> Vec<(String, &Value, &str)> {
+        let mut leaves = Vec::new();
+        collect_leaves(&self.merged, "", &mut leaves);
+        leaves
+            .into_iter()
+            .map(|(path, value)| {
+                let origin = self.origins.get(&path).map_or("default", String::as_str);
+                (path, value, origin)
+            })
+            .collect()
+    }
+}
+
+// Merges, from lowest to highest priority: the legacy
+// ~/.local/share/upsync file, /etc/upsync, $XDG_CONFIG_HOME/upsync, the
+// explicit path and finally UPSYNC_* environment variables. Clients are
+// matched by name across layers.
+pub fn load_layered(explicit: Option<&Path>) -> Result<Layered, Error> {
+    load_layered_with(explicit, None)
+}
+
+// Like load_layered, with the content of one file replaced by an unsaved
+// document so an edit can be validated before it is written. A file that
+// doesn't exist yet is added as the highest priority layer.
+pub(crate) fn load_layered_with(
+    explicit: Option<&Path>,
+    replace: Option<(&Path, &Value)>,
+) -> Result<Layered, Error> {
+    let mut files: Vec<PathBuf> = [
+        super::legacy_config_dir(),
+        PathBuf::from(super::SYSTEM_CONFIG_DIR),
+        super::user_config_dir(),
+    ]
+    .iter()
+    .filter_map(|dir| find_in(dir))
+    .collect();
+
+    if let Some(path) = explicit {
+        if !path.exists() && !matches!(replace, Some((replaced, _)) if replaced == path) {
+            return Err(Error::Config(format!("{} does not exist", path.display())));
+        }
+        files.push(path.to_path_buf());
+    }
+    if let Some((path, _)) = replace {
+        if !files.iter().any(|file| file == path) {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    if files.is_empty() {
+        return Err(Error::Config(format!(
+            "no config file found in {} or {}",
+            super::SYSTEM_CONFIG_DIR,
+            super::user_config_dir().display()
+        )));
+    }
+
+    let mut merged = Value::Object(Map::new());
+    let mut origins = BTreeMap::new();
+    for file in &files {
+        debug!("Loading config layer {}", file.display());
+        let source = file.display().to_string();
+        let layer = match replace {
+            Some((path, value)) if path == file => value.clone(),
+            _ => upgrade(file)?,
+        };
+        merge(&mut merged, layer, "", &source, &mut origins);
+    }
+    apply_env(&mut merged, env::vars(), &mut origins)?;
+
+    let config = parse(merged.clone())?;
+    let problems = validate(&config);
+    if !problems.is_empty() {
+        return Err(invalid("effective config", &problems));
+    }
+
+    Ok(Layered {
+        config,
+        files,
+        origins,
+        merged,
+    })
+}
+
+fn join(path: &str, key: &str) -> String {
+    match path {
+        "" => key.to_string(),
+        _ => format!("{}.{}", path, key),
+    }
+}
+
+fn client_name(client: &Value) -> &str {
+    client
+        .get("name")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+}
+
+fn merge(
+    base: &mut Value,
+    layer: Value,
+    path: &str,
+    source: &str,
+    origins: &mut BTreeMap<String, String>,
+) {
+    let (Value::Object(base), Value::Object(layer)) = (base, layer) else {
+        return;
+    };
+
+    for (key, value) in layer {
+        let field = join(path, &key);
+        if path.is_empty() && key == "clients" && value.is_array() {
+            base.entry("clients")
+                .or_insert_with(|| Value::Array(Vec::new()));
+        }
+        match (base.get_mut(&key), value) {
+            (Some(Value::Array(clients)), Value::Array(layer))
+                if path.is_empty() && key == "clients" =>
+            {
+                for client in layer {
+                    let name = client_name(&client).to_string();
+                    let field = join(&field, &name);
+                    match clients.iter_mut().find(|c| client_name(c) == name) {
+                        Some(existing) => merge(existing, client, &field, source, origins),
+                        None => {
+                            record(&client, &field, source, origins);
+                            clients.push(client);
+                        }
+                    }
+                }
+            }
+            (Some(existing @ Value::Object(_)), value @ Value::Object(_)) => {
+                merge(existing, value, &field, source, origins)
+            }
+            (_, value) => {
+                record(&value, &field, source, origins);
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn record(value: &Value, path: &str, source: &str, origins: &mut BTreeMap<String, String>) {
+    let mut leaves = Vec::new();
+    collect_leaves(value, path, &mut leaves);
+    for (path, _) in leaves {
+        origins.insert(path, source.to_string());
+    }
+}
+
+fn collect_leaves<'a>(value: &'a Value, path: &str, out: &mut Vec<(String, &'a Value)>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                if key == "clients" && path.is_empty() {
+                    for client in value.as_array().into_iter().flatten() {
+                        collect_leaves(client, &join("clients", client_name(client)), out);
+                    }
+                } else {
+                    collect_leaves(value, &join(path, key), out);
+                }
+            }
+        }
+        _ => out.push((path.to_string(), value)),
+    }
+}
+
+// UPSYNC_<FIELD> sets a top-level field and UPSYNC_CLIENTS__<NAME>__<FIELD> a
+// field of one client. Names are upper-cased with everything but letters and
+// digits written as '_', so client 10.0.0.2 becomes 10_0_0_2. Other UPSYNC_*
+// variables, e.g. secrets referenced as env:UPSYNC_..., are skipped.
+fn apply_env(
+    merged: &mut Value,
+    vars: impl Iterator<Item = (String, String)>,
+    origins: &mut BTreeMap<String, String>,
+) -> Result<(), Error> {
+    let (top, client) = samples();
+
+    for (var, raw) in vars {
+        let Some(key) = var.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+
+        let found = match key.split("__").collect::<Vec<_>>().as_slice() {
+            ["CLIENTS", name, field] => {
+                let field = field.to_lowercase();
+                merged
+                    .get_mut("clients")
+                    .and_then(Value::as_array_mut)
+                    .and_then(|clients| {
+                        clients
+                            .iter_mut()
+                            .find(|c| env_name(client_name(c)) == *name)
+                    })
+                    .and_then(|target| {
+                        let sample = client.get(&field)?;
+                        let path = format!("clients.{}.{}", client_name(target), field);
+                        Some((target, sample, path, field))
+                    })
+            }
+            [field] if *field != "CLIENTS" => {
+                let field = field.to_lowercase();
+                top.get(&field)
+                    .map(|sample| (&mut *merged, sample, field.clone(), field))
+            }
+            _ => None,
+        };
+        let Some((target, sample, path, field)) = found else {
+            warn!("Ignoring {}: not a known setting or client", var);
+            continue;
+        };
+
+        let value = coerce(&raw, sample)
+            .ok_or_else(|| Error::Config(format!("{}: '{}' is not a valid value", var, raw)))?;
+        target[&field] = value;
+        origins.insert(path, format!("${}", var));
+    }
+    Ok(())
+}
+
+// Every known setting with its default value, for the top level and for a
+// client, used to check names and types of values given as strings.
+pub(crate) fn samples() -> (Value, Value) {
+    (
+        serde_json::to_value(Config::new(Vec::new())).unwrap_or_default(),
+        serde_json::to_value(ClientConfig::default()).unwrap_or_default(),
+    )
+}
+
+fn env_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect()
+}
+
+// Environment and command line values are always strings; they take the type
+// of the field they override. Lists are written comma separated.
+pub(crate) fn coerce(raw: &str, sample: &Value) -> Option<Value> {
+    match sample {
+        Value::Bool(_) => match raw.to_lowercase().as_str() {
+            "true" | "yes" | "y" | "1" => Some(Value::Bool(true)),
+            "false" | "no" | "n" | "0" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        Value::Number(_) => raw.parse::<u64>().ok().map(Value::from),
+        Value::Array(_) => Some(Value::from(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .collect::<Vec<_>>(),
+        )),
+        _ => Some(Value::String(raw.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merges_clients_by_name_and_env() {
+        let mut merged = json!({});
+        let mut origins = BTreeMap::new();
+        merge(
+            &mut merged,
+            json!({ "version": 2, "clients": [{ "name": "desktop", "user": "a", "key": "k", "ip": "10.0.0.2:22" }] }),
+            "",
+            "/etc/upsync/config.toml",
+            &mut origins,
+        );
+        merge(
+            &mut merged,
+            json!({ "delay_between_tasks": 9, "clients": [{ "name": "desktop", "user": "b" }] }),
+            "",
+            "user.toml",
+            &mut origins,
+        );
+        let vars = vec![
+            (
+                "UPSYNC_CLIENTS__DESKTOP__WAKE".to_string(),
+                "yes".to_string(),
+            ),
+            ("UPSYNC_NAS_PASSWORD".to_string(), "hunter2".to_string()),
+            ("UPSYNC_CLIENTS__LAPTOP__WAKE".to_string(), "no".to_string()),
+        ];
+        apply_env(&mut merged, vars.into_iter(), &mut origins).unwrap();
+
+        let config = parse(merged).unwrap();
+        assert_eq!(config.delay_between_tasks, 9);
+        assert_eq!(config.clients.len(), 1);
+        assert_eq!(config.clients[0].user, "b");
+        assert_eq!(config.clients[0].ip, "10.0.0.2:22");
+        assert!(config.clients[0].wake);
+        assert_eq!(origins["clients.desktop.user"], "user.toml");
+        assert_eq!(origins["clients.desktop.ip"], "/etc/upsync/config.toml");
+        assert_eq!(
+            origins["clients.desktop.wake"],
+            "$UPSYNC_CLIENTS__DESKTOP__WAKE"
+        );
+    }
+}
@@ -0,0 +1,669 @@
+use crate::config::{self, Format, Layered};
+use crate::core::ClientConfig;
+use crate::secret::{self, Secret, Store};
+use crate::{core, server, Error};
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use toml_edit::{ArrayOfTables, DocumentMut, InlineTable, Item, Table, TableLike};
+
+const USAGE: &str = "Usage: upsync config <command> [--config path]
+
+Commands:
+    check              Validate the effective config, or only --config if given.
+    show [--effective] List the config files in use, or print every effective
+                       value and where it came from.
+    get <key>          Print one effective value, e.g. clients.desktop.popup.
+    set <key> <value>  Change one value in the user config (or --config). A
+                       password or agent key is put in the secret store picked
+                       with --secret-store, as in setup.
+    unset <key>        Remove a value, or a whole client with clients.<name>.
+    edit               Open the user config (or --config) in $EDITOR.
+
+A running server is asked to reload after every change.";
+
+// Handles `upsync config <command> ...`.
+pub fn run(args: &[String]) -> Result<(), Error> {
+    let explicit = core::get_flag(args, "--config").map(Path::new);
+    let target = explicit.map_or_else(config::config_path, Path::to_path_buf);
+    let positional = positional(args);
+    match positional.as_slice() {
+        ["check"] => check(explicit),
+        ["show"] => show(explicit, args.iter().any(|arg| arg == "--effective")),
+        ["get", key] => get(explicit, key),
+        ["set", key, value] => {
+            let store = match core::get_flag(args, "--secret-store") {
+                Some(store) => store.parse()?,
+                None => secret::safest_store(),
+            };
+            change(explicit, &target, key, Some((value, store)))
+        }
+        ["unset", key] => change(explicit, &target, key, None),
+        ["edit"] => edit(explicit, &target),
+        _ => Err(Error::Usage(USAGE.to_string())),
+    }
+}
+
+// Arguments that are neither flags nor flag values.
+fn positional(args: &[String]) -> Vec<&str> {
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" | "--secret-store" => {
+                args.next();
+            }
+            flag if flag.starts_with("--") => {}
+            arg => positional.push(arg),
+        }
+    }
+    positional
+}
+
+// Exits non-zero on any problem so it can gate deploy scripts.
+fn check(explicit: Option<&Path>) -> Result<(), Error> {
+    let (source, problems) = match explicit {
+        Some(path) => (path.display().to_string(), config::check(path)?),
+        None => match config::load_layered(None) {
+            Ok(layered) => (files(&layered), Vec::new()),
+            Err(Error::Config(msg)) => {
+                println!("{}", msg);
+                return Err(Error::Check("effective config is invalid".to_string()));
+            }
+            Err(err) => return Err(err),
+        },
+    };
+
+    if problems.is_empty() {
+        println!("{}: OK", source);
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    Err(Error::Check(format!(
+        "{}: {} problem(s) found",
+        source,
+        problems.len()
+    )))
+}
+
+fn show(explicit: Option<&Path>, effective: bool) -> Result<(), Error> {
+    let layered = config::load_layered(explicit)?;
+
+    if !effective {
+        println!("Config files, lowest priority first:");
+        for file in &layered.files {
+            println!("    {}", file.display());
+        }
+        return Ok(());
+    }
+
+    for (path, value, origin) in layered.values() {
+        let value = match secret(&path, value) {
+            true => "\"********\"".to_string(),
+            false => value.to_string(),
+        };
+        println!("{} = {}    # {}", path, value, origin);
+    }
+    Ok(())
+}
+
+// Never print an inline SSH password or agent key, references are safe to show.
+fn secret(path: &str, value: &Value) -> bool {
+    let field = path.rsplit('.').next().unwrap_or_default();
+    ["key", "agent_key"].contains(&field)
+        && value
+            .as_str()
+            .is_some_and(|key| Secret::parse(key).map_or(true, |secret| secret.is_inline()))
+}
+
+fn files(layered: &Layered) -> String {
+    layered
+        .files
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn get(explicit: Option<&Path>, key: &str) -> Result<(), Error> {
+    let layered = config::load_layered(explicit)?;
+    let (_, value, _) = layered
+        .values()
+        .into_iter()
+        .find(|(path, _, _)| path == key)
+        .ok_or_else(|| Error::Usage(format!("Unknown key: {}", key)))?;
+
+    match value {
+        value if secret(key, value) => println!("********"),
+        Value::String(value) => println!("{}", value),
+        value => println!("{}", value),
+    }
+    Ok(())
+}
+
+// Sets (or with None removes) one value in the target file. The change is only
+// written if the resulting effective config is valid.
+fn change(
+    explicit: Option<&Path>,
+    target: &Path,
+    key: &str,
+    raw: Option<(&str, Store)>,
+) -> Result<(), Error> {
+    let before = config::read_document(target)?;
+    let mut document = before.clone();
+    match raw {
+        Some((raw, _)) => set_key(&mut document, key, raw)?,
+        None => unset_key(&mut document, key)?,
+    }
+
+    config::load_layered_with(explicit, Some((target, &document)))?;
+    let shown = match raw {
+        Some((raw, store)) if secret(key, &json!(raw)) => {
+            let reference = store_secret(store, key, raw)?;
+            set_key(&mut document, key, &reference)?;
+            match secret(key, &json!(reference)) {
+                true => "********".to_string(),
+                false => reference,
+            }
+        }
+        Some((raw, _)) => raw.to_string(),
+        None => String::new(),
+    };
+    save(target, &before, &document, key)?;
+    match raw {
+        Some(_) => println!("{} = {} in {}", key, shown, target.display()),
+        None => println!("Removed {} from {}", key, target.display()),
+    }
+    reload()
+}
+
+// Moves a password or agent key out of the config, under the client's name as
+// setup does; agent keys get their own entry.
+fn store_secret(store: Store, key: &str, value: &str) -> Result<String, Error> {
+    let entry = match parse_key(key) {
+        Key::Client(name, Some("agent_key")) => format!("{}-agent", name),
+        Key::Client(name, _) => name.to_string(),
+        Key::Top(field) => field.to_string(),
+    };
+    secret::store(store, &entry, value)
+}
+
+// TOML files are edited in place so comments and order survive. JSON files,
+// and files that were just migrated from an older layout, are written anew.
+fn save(target: &Path, before: &Value, after: &Value, key: &str) -> Result<(), Error> {
+    let text = match Format::of(target) {
+        Format::Toml if target.exists() => fs::read_to_string(target)?,
+        _ => return config::save_document(target, after),
+    };
+    let as_written: Value = toml::from_str(&text)
+        .map_err(|err| Error::Config(format!("Failed to parse TOML: {}", err)))?;
+    if &as_written != before {
+        return config::save_document(target, after);
+    }
+    config::write_atomic(target, &patch_toml(&text, key, after)?)?;
+    Ok(())
+}
+
+// Makes the key in the TOML text what it is in the changed document: set,
+// removed, or for a whole client, gone.
+fn patch_toml(text: &str, key: &str, after: &Value) -> Result<String, Error> {
+    let mut document: DocumentMut = text
+        .parse()
+        .map_err(|err| Error::Config(format!("Failed to parse TOML: {}", err)))?;
+
+    match parse_key(key) {
+        Key::Top(field) => assign(document.as_table_mut(), field, after.get(field)),
+        Key::Client(name, field) => {
+            let client = after["clients"]
+                .as_array()
+                .and_then(|clients| clients.iter().find(|c| c["name"] == name));
+            let index = client_names(&document)
+                .iter()
+                .position(|n| n.as_deref() == Some(name));
+            match (client, field, index) {
+                (None, _, Some(index)) => remove_client(&mut document, index),
+                (Some(client), Some(field), index) => {
+                    let index = index.unwrap_or_else(|| add_client(&mut document, name));
+                    let table = client_table(&mut document, index).ok_or_else(|| {
+                        Error::Config("clients must be a list of tables".to_string())
+                    })?;
+                    assign(table, field, client.get(field));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(document.to_string())
+}
+
+// Sets or removes one field, keeping the comment after an existing value.
+fn assign(table: &mut dyn TableLike, field: &str, value: Option<&Value>) {
+    let Some(mut value) = value.and_then(toml_value) else {
+        table.remove(field);
+        return;
+    };
+    match table.get_mut(field) {
+        Some(item) => {
+            if let Some(old) = item.as_value() {
+                *value.decor_mut() = old.decor().clone();
+            }
+            *item = Item::Value(value);
+        }
+        None => {
+            table.insert(field, Item::Value(value));
+        }
+    }
+}
+
+fn toml_value(value: &Value) -> Option<toml_edit::Value> {
+    Some(match value {
+        Value::Null => return None,
+        Value::Bool(value) => (*value).into(),
+        Value::Number(number) => match number.as_i64() {
+            Some(number) => number.into(),
+            None => number.as_f64()?.into(),
+        },
+        Value::String(value) => value.as_str().into(),
+        Value::Array(items) => items
+            .iter()
+            .map(toml_value)
+            .collect::<Option<toml_edit::Array>>()?
+            .into(),
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(key, value)| Some((key.as_str(), toml_value(value)?)))
+            .collect::<Option<InlineTable>>()?
+            .into(),
+    })
+}
+
+// Clients are usually [[clients]] tables, but may be an inline list.
+fn client_names(document: &DocumentMut) -> Vec<Option<String>> {
+    let name = |name: Option<&str>| name.map(str::to_string);
+    match document.get("clients") {
+        Some(Item::ArrayOfTables(tables)) => tables
+            .iter()
+            .map(|table| name(table.get("name").and_then(Item::as_str)))
+            .collect(),
+        Some(Item::Value(toml_edit::Value::Array(items))) => items
+            .iter()
+            .map(|item| {
+                name(
+                    item.as_inline_table()
+                        .and_then(|table| table.get("name"))
+                        .and_then(toml_edit::Value::as_str),
+                )
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn client_table(document: &mut DocumentMut, index: usize) -> Option<&mut dyn TableLike> {
+    match document.get_mut("clients")? {
+        Item::ArrayOfTables(tables) => tables
+            .get_mut(index)
+            .map(|table| table as &mut dyn TableLike),
+        Item::Value(toml_edit::Value::Array(items)) => items
+            .get_mut(index)?
+            .as_inline_table_mut()
+            .map(|table| table as &mut dyn TableLike),
+        _ => None,
+    }
+}
+
+// Returns the index of the new client.
+fn add_client(document: &mut DocumentMut, name: &str) -> usize {
+    let mut table = Table::new();
+    table.insert("name", toml_edit::value(name));
+    match document.get_mut("clients") {
+        Some(Item::Value(toml_edit::Value::Array(items))) => {
+            items.push(table.into_inline_table());
+            items.len() - 1
+        }
+        Some(Item::ArrayOfTables(tables)) => {
+            tables.push(table);
+            tables.len() - 1
+        }
+        _ => {
+            let mut tables = ArrayOfTables::new();
+            tables.push(table);
+            document.insert("clients", Item::ArrayOfTables(tables));
+            0
+        }
+    }
+}
+
+fn remove_client(document: &mut DocumentMut, index: usize) {
+    match document.get_mut("clients") {
+        Some(Item::ArrayOfTables(tables)) => tables.remove(index),
+        Some(Item::Value(toml_edit::Value::Array(items))) => {
+            items.remove(index);
+        }
+        _ => {}
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Key<'a> {
+    Top(&'a str),
+    Client(&'a str, Option<&'a str>),
+}
+
+// "clients.<name>.<field>" or "clients.<name>"; client names may contain dots.
+fn parse_key(key: &str) -> Key<'_> {
+    let (_, client) = config::samples();
+    match key.strip_prefix("clients.") {
+        Some(rest) => match rest.rsplit_once('.') {
+            Some((name, field)) if client.get(field).is_some() => Key::Client(name, Some(field)),
+            _ => Key::Client(rest, None),
+        },
+        None => Key::Top(key),
+    }
+}
+
+fn set_key(document: &mut Value, key: &str, raw: &str) -> Result<(), Error> {
+    let (top, client) = config::samples();
+    let unknown = || Error::Usage(format!("Unknown key: {}", key));
+
+    match parse_key(key) {
+        Key::Top("version") => Err(Error::Usage("version is managed by upsync".to_string())),
+        Key::Top(field) => {
+            let sample = top
+                .get(field)
+                .filter(|_| field != "clients")
+                .ok_or_else(unknown)?;
+            document[field] = typed(raw, sample, field, &client)?;
+            Ok(())
+        }
+        Key::Client(_, None) => Err(unknown()),
+        Key::Client(name, Some(field)) => {
+            let value = typed(raw, &client[field], field, &client)?;
+            let clients = document
+                .as_object_mut()
+                .ok_or_else(|| Error::Config("expected a table".to_string()))?
+                .entry("clients")
+                .or_insert_with(|| json!([]))
+                .as_array_mut()
+                .ok_or_else(|| Error::Config("clients must be a list".to_string()))?;
+
+            // A client only defined in a lower layer gets a partial entry here.
+            let index = match clients.iter().position(|c| c["name"] == name) {
+                Some(index) => index,
+                None => {
+                    clients.push(json!({ "name": name }));
+                    clients.len() - 1
+                }
+            };
+            clients[index][field] = value;
+            Ok(())
+        }
+    }
+}
+
+// Converts the string to the field's type and checks it deserializes, so e.g.
+// default_behaviour only takes one of the Behaviour names.
+fn typed(raw: &str, sample: &Value, field: &str, client: &Value) -> Result<Value, Error> {
+    let invalid = || Error::Input(format!("'{}' is not a valid value for {}", raw, field));
+    let value = config::coerce(raw, sample).ok_or_else(invalid)?;
+
+    if client.get(field).is_some() {
+        let mut check = client.clone();
+        check[field] = value.clone();
+        if serde_json::from_value::<ClientConfig>(check).is_err() {
+            // Accept "hibernate" for "Hibernate".
+            let capitalized = capitalize(raw);
+            let mut check = client.clone();
+            check[field] = json!(capitalized);
+            return match serde_json::from_value::<ClientConfig>(check) {
+                Ok(_) => Ok(json!(capitalized)),
+                Err(_) => Err(invalid()),
+            };
+        }
+    }
+    Ok(value)
+}
+
+fn capitalize(raw: &str) -> String {
+    let lower = raw.to_lowercase();
+    let mut chars = lower.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn unset_key(document: &mut Value, key: &str) -> Result<(), Error> {
+    let missing = || Error::Usage(format!("{} is not set in this file", key));
+
+    match parse_key(key) {
+        Key::Top("version") | Key::Top("clients") => {
+            Err(Error::Usage(format!("{} can't be removed", key)))
+        }
+        Key::Top(field) => document
+            .as_object_mut()
+            .and_then(|top| top.remove(field))
+            .map(|_| ())
+            .ok_or_else(missing),
+        Key::Client(name, field) => {
+            let clients = document
+                .get_mut("clients")
+                .and_then(Value::as_array_mut)
+                .ok_or_else(missing)?;
+            let index = clients
+                .iter()
+                .position(|c| c["name"] == name)
+                .ok_or_else(missing)?;
+            match field {
+                None => {
+                    clients.remove(index);
+                    Ok(())
+                }
+                Some("name") => Err(Error::Usage(
+                    "a client's name can be changed but not removed".to_string(),
+                )),
+                Some(field) => clients[index]
+                    .as_object_mut()
+                    .and_then(|client| client.remove(field))
+                    .map(|_| ())
+                    .ok_or_else(missing),
+            }
+        }
+    }
+}
+
+// Edits a private copy and only replaces the real file once the result is
+// valid. The text is kept as written, comments included.
+fn edit(explicit: Option<&Path>, target: &Path) -> Result<(), Error> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let extension = match Format::of(target) {
+        Format::Json => "json",
+        Format::Toml => "toml",
+    };
+    let copy = target.with_extension(format!("edit.{}", extension));
+
+    let original = match target.exists() {
+        true => fs::read_to_string(target)?,
+        false => String::new(),
+    };
+    config::write_atomic(&copy, &original)?;
+
+    let result = loop {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", editor))
+            .arg("sh")
+            .arg(&copy)
+            .status()?;
+        if !status.success() {
+            break Err(Error::Command(format!("{} exited with {}", editor, status)));
+        }
+
+        let edited = fs::read_to_string(&copy)?;
+        if edited == original {
+            println!("No changes.");
+            break Ok(false);
+        }
+
+        match config::read_document(&copy)
+            .and_then(|document| config::load_layered_with(explicit, Some((target, &document))))
+        {
+            Ok(_) => {
+                config::write_atomic(target, &edited)?;
+                println!("Saved {}", target.display());
+                break Ok(true);
+            }
+            Err(err) => {
+                println!("{}", err);
+                println!("Edit again? (y/n)");
+                if core::user_input()?.to_lowercase() != "y" {
+                    break Err(Error::Cancelled);
+                }
+            }
+        }
+    };
+
+    let _ = fs::remove_file(&copy);
+    match result? {
+        true => reload(),
+        false => Ok(()),
+    }
+}
+
+fn reload() -> Result<(), Error> {
+    match server::signal_reload()? {
+        true => println!("Asked the running server to reload."),
+        false => println!("No running server found, the change applies on next start."),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(
+            parse_key("delay_between_tasks"),
+            Key::Top("delay_between_tasks")
+        );
+        assert_eq!(
+            parse_key("clients.10.0.0.2.popup"),
+            Key::Client("10.0.0.2", Some("popup"))
+        );
+        assert_eq!(parse_key("clients.desktop"), Key::Client("desktop", None));
+        assert_eq!(
+            parse_key("clients.desktop.bogus"),
+            Key::Client("desktop.bogus", None)
+        );
+    }
+
+    #[test]
+    fn sets_and_unsets_keys() {
+        let mut document = json!({
+            "version": 2,
+            "clients": [{ "name": "desktop", "user": "dhanu" }]
+        });
+
+        set_key(&mut document, "delay_between_tasks", "9").unwrap();
+        set_key(&mut document, "clients.desktop.popup", "no").unwrap();
+        set_key(&mut document, "clients.laptop.wake", "yes").unwrap();
+        assert_eq!(document["delay_between_tasks"], 9);
+        assert_eq!(document["clients"][0]["popup"], false);
+        assert_eq!(
+            document["clients"][1],
+            json!({ "name": "laptop", "wake": true })
+        );
+
+        assert!(set_key(&mut document, "version", "3").is_err());
+        assert!(set_key(&mut document, "bogus", "1").is_err());
+        assert!(set_key(&mut document, "clients.desktop", "x").is_err());
+        assert!(set_key(&mut document, "delay_between_tasks", "soon").is_err());
+
+        unset_key(&mut document, "clients.desktop.user").unwrap();
+        unset_key(&mut document, "clients.laptop").unwrap();
+        unset_key(&mut document, "delay_between_tasks").unwrap();
+        assert_eq!(
+            document,
+            json!({ "version": 2, "clients": [{ "name": "desktop", "popup": false }] })
+        );
+
+        assert!(unset_key(&mut document, "version").is_err());
+        assert!(unset_key(&mut document, "clients.desktop.name").is_err());
+        assert!(unset_key(&mut document, "clients.desktop.user").is_err());
+        assert!(unset_key(&mut document, "clients.nas").is_err());
+    }
+
+    #[test]
+    fn converts_values_to_the_field_type() {
+        let (top, client) = config::samples();
+        let behaviour = &client["default_behaviour"];
+        assert_eq!(
+            typed("yes", &client["popup"], "popup", &client).unwrap(),
+            true
+        );
+        assert_eq!(
+            typed("hibernate", behaviour, "default_behaviour", &client).unwrap(),
+            "Hibernate"
+        );
+        assert!(typed("nap", behaviour, "default_behaviour", &client).is_err());
+        assert!(typed(
+            "soon",
+            &top["delay_between_tasks"],
+            "delay_between_tasks",
+            &client
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn edits_toml_in_place() {
+        let text = "\
+# Written by hand.
+version = 2
+delay_between_tasks = 5 # seconds
+
+[[clients]]
+# The big one.
+name = \"desktop\"
+popup = true
+";
+        let mut document: Value = toml::from_str(text).unwrap();
+        set_key(&mut document, "delay_between_tasks", "9").unwrap();
+        let text = patch_toml(text, "delay_between_tasks", &document).unwrap();
+        set_key(&mut document, "clients.laptop.wake", "yes").unwrap();
+        let text = patch_toml(&text, "clients.laptop.wake", &document).unwrap();
+        unset_key(&mut document, "clients.desktop.popup").unwrap();
+        let text = patch_toml(&text, "clients.desktop.popup", &document).unwrap();
+
+        assert_eq!(
+            text,
+            "\
+# Written by hand.
+version = 2
+delay_between_tasks = 9 # seconds
+
+[[clients]]
+# The big one.
+name = \"desktop\"
+
+[[clients]]
+name = \"laptop\"
+wake = true
+"
+        );
+        assert_eq!(toml::from_str::<Value>(&text).unwrap(), document);
+
+        unset_key(&mut document, "clients.laptop").unwrap();
+        let text = patch_toml(&text, "clients.laptop", &document).unwrap();
+        assert!(!text.contains("laptop"));
+    }
+}
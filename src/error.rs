@@ -0,0 +1,83 @@
+use std::{fmt, io};
+
+// Every fallible function in the library returns this; only main.rs turns it
+// into an exit code. New variants may be added, so matches outside the crate
+// need a catch-all arm.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    // Missing, unreadable or invalid configuration.
+    Config(String),
+    // A secret reference could not be resolved or stored.
+    Secret(String),
+    // Invalid command line arguments.
+    Usage(String),
+    // Invalid answers given to the interactive setup.
+    Input(String),
+    // The user declined to continue.
+    Cancelled,
+    // The SSH session could not be established or a channel failed.
+    Ssh(ssh2::Error),
+    // The client rejected the configured credentials.
+    Auth(String),
+    // The user may log in but not run the command, e.g. a polkit denial.
+    Permission(String),
+    // The client could not be reached over the network.
+    Network(io::Error),
+    // The battery state could not be read.
+    Battery(battery::Error),
+    Io(io::Error),
+    // A local or remote command ran but exited unsuccessfully.
+    Command(String),
+    // A check or drill ran but reported failures.
+    Check(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(msg) => write!(f, "config error: {}", msg),
+            Error::Secret(msg) => write!(f, "secret error: {}", msg),
+            Error::Usage(msg) => write!(f, "{}", msg),
+            Error::Input(msg) => write!(f, "invalid input: {}", msg),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::Ssh(err) => write!(f, "ssh error: {}", err),
+            Error::Auth(msg) => write!(f, "authentication failed: {}", msg),
+            Error::Permission(msg) => write!(f, "permission denied: {}", msg),
+            Error::Network(err) => write!(f, "network error: {}", err),
+            Error::Battery(err) => write!(f, "battery error: {}", err),
+            Error::Io(err) => write!(f, "io error: {}", err),
+            Error::Command(msg) => write!(f, "command failed: {}", msg),
+            Error::Check(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Ssh(err) => Some(err),
+            Error::Network(err) | Error::Io(err) => Some(err),
+            Error::Battery(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<ssh2::Error> for Error {
+    fn from(err: ssh2::Error) -> Self {
+        Error::Ssh(err)
+    }
+}
+
+impl From<battery::Error> for Error {
+    fn from(err: battery::Error) -> Self {
+        Error::Battery(err)
+    }
+}
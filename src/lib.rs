@@ -1,168 +1,266 @@
+mod agent;
+mod client;
+mod client_shell;
+pub mod config;
+mod config_cmd;
+mod error;
+pub mod monitor;
+mod protocol;
+pub mod secret;
 mod server;
 mod setup;
+pub mod state;
+
+pub use error::Error;
+pub use monitor::{Event, Monitor};
 
 pub mod core {
     use battery;
     use log::debug;
-    use std::{env, error::Error, fs, io, process};
+    use std::{env, fs, io, process};
     use tokio::net::TcpStream;
     use tokio::time::{timeout, Duration};
     // This enum represents the JSON structure
     use serde::{Deserialize, Serialize};
 
-    use crate::{server, setup};
+    use crate::{agent, client_shell, config_cmd, server, setup, Error};
 
     pub const APPNAME: &str = "upsync";
     pub const GUI_APPNAME: &str = "upsync-gui";
 
-    #[derive(Serialize, Deserialize, Debug)]
+    // Optional fields carry serde defaults so older config files keep loading.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct ClientConfig {
+        pub name: String,
         pub user: String,
         pub key: String,
         pub ip: String,
+        #[serde(default)]
         pub wake: bool,
+        #[serde(default)]
         pub mac_address: String,
+        #[serde(default)]
         pub default_behaviour: Behaviour,
+        #[serde(default = "default_delay")]
         pub default_delay: u32,
-        pub delay_between_tasks: u64,
+        #[serde(default = "default_popup")]
         pub popup: bool,
+        // Apply the default behaviour right away when nobody is using the
+        // client instead of showing the popup to an empty room.
+        #[serde(default = "default_skip_popup_when_idle")]
+        pub skip_popup_when_idle: bool,
+        // Seconds the client gets to stop answering after a power command
+        // before the next deeper state is tried.
+        #[serde(default = "default_power_deadline")]
+        pub power_deadline: u32,
+        // Seconds to wait for the client after WOL, doubled on every retry.
+        #[serde(default = "default_wake_timeout")]
+        pub wake_timeout: u32,
+        #[serde(default = "default_wake_retries")]
+        pub wake_retries: u32,
+        // Run over SSH once a woken client answers, empty for none.
+        #[serde(default)]
+        pub post_wake_command: String,
+        // Lower wakes first.
+        #[serde(default)]
+        pub wake_priority: u32,
+        // Names of clients that have to answer before this one is woken.
+        #[serde(default)]
+        pub wake_after: Vec<String>,
+        // Lower is acted upon first during an outage.
+        #[serde(default)]
+        pub shutdown_priority: u32,
+        // Names of clients that have to be down before this one is acted upon.
+        #[serde(default)]
+        pub shutdown_after: Vec<String>,
+        // Parts of command lines, e.g. "pacman" or "restic backup", that hold
+        // back the action while such a process runs.
+        #[serde(default)]
+        pub critical_processes: Vec<String>,
+        // How the client is put into each power state.
+        #[serde(default)]
+        pub commands: Commands,
+        // The SSH account only allows `upsync client-shell`, so its verbs are
+        // sent instead of shell commands and the client picks the commands.
+        #[serde(default)]
+        pub client_shell: bool,
+        // host:port of `upsync agent` on the client, which then shows the
+        // popup instead of SSH. Empty for none.
+        #[serde(default)]
+        pub agent: String,
+        // Secret reference to the key the agent was started with.
+        #[serde(default)]
+        pub agent_key: String,
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
-    pub enum Behaviour {
-        Sleep,
-        Hibernate,
-        Shutdown,
-        Ignore,
-    }
-
-    impl ClientConfig {
-        pub fn new() -> ClientConfig {
+    impl Default for ClientConfig {
+        fn default() -> Self {
             ClientConfig {
-                user: parse_input_string("Enter the client username: ",false),
-                key: parse_input_string("Enter the user password: ",false),
-                ip: parse_input_string("Enter the IP address of your device with the ssh port by default it is 22 (e.g., 192.168.66.99:22): )", false),
-                wake: get_yes_no_input("Do you want to wake the PC automatically when the power is restored using WOL (Wake-on-LAN)? (y/n) [Default: n]: ",false),
-                mac_address: parse_input_string("Enter the MAC address of your device (Leave blank if you did not choose to enable Wake-on-LAN): ",true),   
-                default_delay:parse_input_u32("Enter the time (in seconds) after power loss to put the device to default behaviour: \nDefault: 30"),    
-                default_behaviour: parse_input_behaviour("Default behaviour when power is out: \n1 = Sleep\n2 = Hybernate\n3 = Shutdown\n4 = Do nothing \nDefault: 1 "),         
-                delay_between_tasks: 5,
-                popup: get_yes_no_input("Do you want to see the popup when power is out? (y/n): \nDefault: y", true),
+                name: String::new(),
+                user: String::new(),
+                key: String::new(),
+                ip: String::new(),
+                wake: false,
+                mac_address: String::new(),
+                default_behaviour: Behaviour::default(),
+                default_delay: default_delay(),
+                popup: default_popup(),
+                skip_popup_when_idle: default_skip_popup_when_idle(),
+                power_deadline: default_power_deadline(),
+                wake_timeout: default_wake_timeout(),
+                wake_retries: default_wake_retries(),
+                post_wake_command: String::new(),
+                wake_priority: 0,
+                wake_after: Vec::new(),
+                shutdown_priority: 0,
+                shutdown_after: Vec::new(),
+                critical_processes: Vec::new(),
+                commands: Commands::default(),
+                client_shell: false,
+                agent: String::new(),
+                agent_key: String::new(),
             }
         }
     }
 
-    fn get_input(prompt: &str) -> String {
-        println!("{}", prompt);
-        user_input().expect("Failed to read input")
+    fn default_delay() -> u32 {
+        30
     }
 
-    fn parse_input_string(prompt: &str, act: bool) -> String {
-        let mut attempts = 0;
+    fn default_popup() -> bool {
+        true
+    }
 
-        while attempts < 3 {
-            let input = get_input(prompt);
+    fn default_skip_popup_when_idle() -> bool {
+        true
+    }
 
-            if input.is_empty() && !act {
-                eprintln!("Invalid input.");
-                attempts += 1;
-                continue;
-            }
-            return input;
-        }
+    fn default_power_deadline() -> u32 {
+        60
+    }
 
-        println!("Exceeded maximum attempts.");
-        std::process::exit(1);
+    fn default_wake_timeout() -> u32 {
+        120
     }
 
-    fn parse_input_u32(prompt: &str) -> u32 {
-        let mut attempts: u64 = 0;
+    fn default_wake_retries() -> u32 {
+        3
+    }
 
-        while attempts < 3 {
-            let input = get_input(prompt);
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    pub enum Behaviour {
+        #[default]
+        Sleep,
+        Hibernate,
+        Shutdown,
+        Ignore,
+    }
 
-            if input.is_empty() {
-                return 30;
-            }
+    // Where the power commands come from when a client doesn't set its own.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum Preset {
+        #[default]
+        Systemd,
+        // OpenRC, runit and s6 without elogind: the kernel interface directly.
+        Openrc,
+        // elogind
+        Loginctl,
+        PmUtils,
+    }
 
-            match input.parse::<u32>() {
-                Ok(x) => return x,
-                Err(_) => {
-                    eprintln!(
-                        "Invalid input. Please enter a valid number of seconds as an integer."
-                    );
-                    attempts += 1;
-                }
-            }
-        }
+    // The command for each power state, e.g.
+    //
+    //   [clients.commands]
+    //   preset = "openrc"
+    //   sudo = true
+    //   suspend = "zzz"
+    //
+    // An empty command falls back to the preset.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    pub struct Commands {
+        #[serde(default)]
+        pub preset: Preset,
+        // Prefix every command with `sudo -n`, which fails instead of asking
+        // for a password nobody can type.
+        #[serde(default)]
+        pub sudo: bool,
+        #[serde(default)]
+        pub suspend: String,
+        #[serde(default)]
+        pub hibernate: String,
+        #[serde(default)]
+        pub hybrid_sleep: String,
+        #[serde(default)]
+        pub poweroff: String,
+    }
 
-        println!("Exceeded maximum attempts. Exiting or using default value.");
-        std::process::exit(1);
-    }
-
-    fn get_yes_no_input(prompt: &str, default: bool) -> bool {
-        let mut attempts = 0;
-
-        while attempts < 3 {
-            println!("{}", prompt);
-            let input = user_input().unwrap_or_default().trim().to_lowercase();
-            match input.as_str() {
-                // Default to "yes" if the user presses Enter
-                "" => return default,
-                "y" => return true,
-                "n" => return false,
-                _ => {
-                    eprintln!("Invalid input. Please enter 'y' for yes or 'n' for no.");
-                    attempts += 1;
-                }
-            }
+    impl Commands {
+        // The shell command for a systemctl verb, None if neither the client
+        // nor its preset has one.
+        pub fn command(&self, action: &str) -> Option<String> {
+            let custom = match action {
+                "suspend" => &self.suspend,
+                "hibernate" => &self.hibernate,
+                "hybrid-sleep" => &self.hybrid_sleep,
+                "poweroff" => &self.poweroff,
+                _ => return None,
+            };
+            let command = match custom.is_empty() {
+                true => preset_command(self.preset, action)?.to_string(),
+                false => custom.clone(),
+            };
+            Some(match self.sudo {
+                true => format!("sudo -n {}", command),
+                false => command,
+            })
         }
-
-        println!("Exceeded maximum attempts.");
-        std::process::exit(1);
     }
 
-    fn parse_input_behaviour(prompt: &str) -> Behaviour {
-        let mut attempts = 0;
-
-        while attempts < 3 {
-            let input = get_input(prompt);
-
-            if input.is_empty() {
-                return Behaviour::Sleep;
-            }
-
-            match input.parse::<u8>() {
-                Ok(1) => return Behaviour::Sleep,
-                Ok(2) => return Behaviour::Hibernate,
-                Ok(3) => return Behaviour::Shutdown,
-                Ok(4) => return Behaviour::Ignore,
-                _ => {
-                    eprintln!("Invalid input. Please enter a choice between 1 and 4.");
-                    attempts += 1;
-                }
-            }
+    fn preset_command(preset: Preset, action: &str) -> Option<&'static str> {
+        match (preset, action) {
+            (Preset::Systemd, "suspend") => Some("systemctl suspend"),
+            (Preset::Systemd, "hibernate") => Some("systemctl hibernate"),
+            (Preset::Systemd, "hybrid-sleep") => Some("systemctl hybrid-sleep"),
+            (Preset::Systemd, "poweroff") => Some("systemctl poweroff"),
+            (Preset::Openrc, "suspend") => Some("sh -c 'echo mem > /sys/power/state'"),
+            (Preset::Openrc, "hibernate") => Some("sh -c 'echo disk > /sys/power/state'"),
+            (Preset::Openrc, "poweroff") => Some("poweroff"),
+            (Preset::Loginctl, "suspend") => Some("loginctl suspend"),
+            (Preset::Loginctl, "hibernate") => Some("loginctl hibernate"),
+            (Preset::Loginctl, "hybrid-sleep") => Some("loginctl hybrid-sleep"),
+            (Preset::Loginctl, "poweroff") => Some("loginctl poweroff"),
+            (Preset::PmUtils, "suspend") => Some("pm-suspend"),
+            (Preset::PmUtils, "hibernate") => Some("pm-hibernate"),
+            (Preset::PmUtils, "hybrid-sleep") => Some("pm-suspend-hybrid"),
+            (Preset::PmUtils, "poweroff") => Some("poweroff"),
+            _ => None,
         }
-        println!("Exceeded maximum attempts.");
-        std::process::exit(1);
+    }
+
+    // The environment variable the popup finds the command for an action in.
+    pub fn command_env(action: &str) -> String {
+        format!("COMMAND_{}", action.to_uppercase().replace('-', "_"))
     }
 
     // On my laptop, if the battery is full, it reports "unknown" instead of "full."
     // As a workaround, run_server() assumes "unknown" means the battery is charging.
-    pub fn battery_present() -> Result<battery::State, battery::Error> {
+    pub fn battery_present() -> Result<battery::State, Error> {
         let manager = battery::Manager::new()?;
 
         let battery = match manager.batteries()?.next() {
             Some(Ok(battery)) => battery,
-            Some(Err(e)) => return Err(e),
+            Some(Err(e)) => return Err(e.into()),
             None => {
-                return Err(io::Error::from(io::ErrorKind::NotFound).into());
+                return Err(Error::Battery(
+                    io::Error::from(io::ErrorKind::NotFound).into(),
+                ));
             }
         };
-        return Ok(battery.state());
+        Ok(battery.state())
     }
 
-    pub fn run_command(config: &str) -> Result<bool, io::Error> {
+    pub fn run_command(config: &str) -> Result<bool, Error> {
         let command = config;
         let output = process::Command::new("sh")
             .arg("-c")
@@ -173,7 +271,7 @@ pub mod core {
     }
 
     #[tokio::main]
-    pub async fn device_status(ip: &str) -> Result<bool, Box<dyn Error>> {
+    pub async fn device_status(ip: &str) -> Result<bool, Error> {
         let timeout_duration = Duration::from_secs(3);
 
         match timeout(timeout_duration, TcpStream::connect(ip)).await {
@@ -187,11 +285,42 @@ pub mod core {
         unimplemented!()
     }
 
-    pub fn get_args() -> String {
-        env::args()
-            .skip(1)
-            .next()
-            .unwrap_or_else(|| "default".to_string())
+    pub fn get_args() -> Vec<String> {
+        env::args().skip(1).collect()
+    }
+
+    // Returns the value following `name` in args, e.g. `--duration 120s`.
+    pub fn get_flag<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|i| args.get(i + 1))
+            .map(String::as_str)
+    }
+
+    fn config_flag(args: &[String]) -> Option<&std::path::Path> {
+        get_flag(args, "--config").map(std::path::Path::new)
+    }
+
+    // Accepts plain seconds or a number with an s/m/h suffix (e.g. 90, 120s, 2m).
+    pub fn parse_duration(input: &str) -> Option<Duration> {
+        let input = input.trim();
+        let (number, unit) = match input.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => input.split_at(i),
+            None => (input, "s"),
+        };
+        let number: u64 = number.parse().ok()?;
+        let seconds = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            _ => return None,
+        };
+        number.checked_mul(seconds).map(Duration::from_secs)
+    }
+
+    // "192.168.1.240:22" -> "192.168.1.240"
+    pub fn host_of(ip: &str) -> &str {
+        ip.rsplit_once(':').map_or(ip, |(host, _)| host)
     }
 
     pub fn get_env(env: &str) -> String {
@@ -201,27 +330,28 @@ pub mod core {
         }
     }
 
-    pub fn read_json<T: for<'de> Deserialize<'de>>(
-        path: &std::path::Path,
-    ) -> Result<T, Box<dyn std::error::Error>> {
+    pub fn read_json<T: for<'de> Deserialize<'de>>(path: &std::path::Path) -> Result<T, Error> {
         debug!("{}", path.display());
-        let data = fs::read_to_string(path).map_err(|e| format!("Failed to read file: {}", e))?;
-        let json: T =
-            serde_json::from_str(&data).map_err(|e| format!("Failed to parse JSON: {}", e))?;
+        let data = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read file: {}", e)))?;
+        let json: T = serde_json::from_str(&data)
+            .map_err(|e| Error::Config(format!("Failed to parse JSON: {}", e)))?;
         Ok(json)
     }
 
-    pub fn user_input() -> Result<String, io::Error> {
+    pub fn user_input() -> Result<String, Error> {
         let mut input = String::new();
         io::stdin().read_line(&mut input)?;
         Ok(input.trim().to_string())
     }
 
+    // Accepts the popup's own names and the systemctl verbs.
     pub fn get_default(action: &str) -> String {
         match action {
             "suspend" => String::from("suspend"),
-            "hybernate" => String::from("hibernate"),
-            "shutdown" => String::from("poweroff"),
+            "hybernate" | "hibernate" => String::from("hibernate"),
+            "hybrid-sleep" => String::from("hybrid-sleep"),
+            "shutdown" | "poweroff" => String::from("poweroff"),
             _ => String::from(""),
         }
     }
@@ -235,23 +365,56 @@ pub mod core {
         }
     }
 
-    pub fn run(inputs: String) {
-        match inputs.as_str() {
-            "setup" => setup::server_setup(),
-            "server" => server::run_server(),
+    pub fn run(args: Vec<String>) -> Result<(), Error> {
+        match args.first().map(String::as_str).unwrap_or("default") {
+            "setup" => setup::server_setup(&args[1..]),
+            "server" => server::run_server(config_flag(&args)),
+            "config" => config_cmd::run(&args[1..]),
+            "client-shell" => client_shell::run(&args[1..]),
+            "agent" => agent::run(&args[1..]),
+            "simulate-outage" => {
+                let duration = match get_flag(&args, "--duration") {
+                    Some(value) => parse_duration(value)
+                        .ok_or_else(|| Error::Usage(format!("Invalid duration: {}", value)))?,
+                    None => Duration::from_secs(120),
+                };
+                server::simulate_outage(config_flag(&args), duration, get_flag(&args, "--client"))
+            }
             _ => {
                 println!(
                     r#"{}: Convert a non-smart UPS into a smart UPS using laptop power states.
     
-    Usage: {} <command>
+    Usage: {} <command> [--config path]
     
     Commands:
-    setup      Initialize the application (e.g., on a laptop).
+    setup [--yes] [--skip-checks] [--replace] [--answers file] [--<field> value ...]
+               Add a client to the config, or update the one with the same
+               name; --replace starts a new config instead. Every
+               question can be answered up front, e.g. --user, --key, --ip,
+               --wake, --mac-address, --default-behaviour. The answers are
+               tried against the client before saving unless --skip-checks.
+               The password is moved out of the config into
+               --secret-store plain|file|credential|keyring|age, by default
+               the safest one available; --key env:VAR keeps a reference.
     server     Start the power monitoring server.
+    simulate-outage [--duration 120s] [--client <name>]
+               Run the monitor with the power out for the given time
+               (seconds, or with s, m or h) and print a report.
+    config check
+               Validate the config and report every problem.
+    config show [--effective]
+               Show the config files in use or the merged values.
     client     Run this on the client to see the demo popup.
+    client-shell [--preset systemd|openrc|loginctl|pm-utils] [--sudo]
+               Forced command for the client's SSH account: runs only the
+               upsync verbs in SSH_ORIGINAL_COMMAND and logs the rest.
+    agent --key <secret> [--listen 0.0.0.0:7654] [--preset ...] [--sudo]
+               Run on the client in the user's session to show the popup
+               when the server asks, instead of over SSH.
     "#,
                     APPNAME, APPNAME
-                )
+                );
+                Ok(())
             }
         }
     }
@@ -260,6 +423,44 @@ pub mod core {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(core::parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(core::parse_duration(" 45s "), Some(Duration::from_secs(45)));
+        assert_eq!(core::parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(core::parse_duration("1h"), Some(Duration::from_secs(3600)));
+
+        assert_eq!(core::parse_duration(""), None);
+        assert_eq!(core::parse_duration("m"), None);
+        assert_eq!(core::parse_duration("-5s"), None);
+        assert_eq!(core::parse_duration("1.5m"), None);
+        assert_eq!(core::parse_duration("10d"), None);
+        assert_eq!(core::parse_duration("18446744073709551615h"), None);
+    }
+
+    #[test]
+    fn builds_power_commands() {
+        let systemd = core::Commands::default();
+        assert_eq!(
+            systemd.command("suspend").as_deref(),
+            Some("systemctl suspend")
+        );
+
+        let openrc = core::Commands {
+            preset: core::Preset::Openrc,
+            sudo: true,
+            suspend: "zzz".to_string(),
+            ..core::Commands::default()
+        };
+        assert_eq!(openrc.command("suspend").as_deref(), Some("sudo -n zzz"));
+        assert_eq!(
+            openrc.command("poweroff").as_deref(),
+            Some("sudo -n poweroff")
+        );
+        assert_eq!(openrc.command("hybrid-sleep"), None);
+    }
 
     #[test]
     fn test_tokio() {
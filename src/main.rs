@@ -1,10 +1,30 @@
 use env_logger::{Builder, Env};
-use upsync::core;
+use log::error;
+use std::process;
+use upsync::{core, Error};
 
 fn main() {
     let env = Env::default().filter_or("LOG", "info");
     Builder::from_env(env).init();
 
     // Everything in here is temporary for debugging and testing.
-    core::run(core::get_args());
+    if let Err(err) = core::run(core::get_args()) {
+        error!("{}", err);
+        process::exit(exit_code(&err));
+    }
+}
+
+// Exit codes follow sysexits.h where one fits.
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::Cancelled => 0,
+        Error::Check(_) | Error::Command(_) => 1,
+        Error::Usage(_) | Error::Input(_) => 64,
+        Error::Network(_) | Error::Battery(_) => 69,
+        Error::Io(_) => 74,
+        Error::Ssh(_) => 76,
+        Error::Auth(_) | Error::Permission(_) => 77,
+        Error::Config(_) | Error::Secret(_) => 78,
+        _ => 1,
+    }
 }
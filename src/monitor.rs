@@ -0,0 +1,1351 @@
+use crate::core::{self, ClientConfig};
+use crate::state::{self, ClientState, OutageState, Phase};
+use crate::{agent, client, Error};
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    Mains,
+    Battery,
+}
+
+// Anything that can tell whether mains power is present.
+pub trait PowerSource: Send {
+    fn state(&mut self) -> Result<PowerState, Error>;
+}
+
+// Infers mains power from the charging state of the laptop running upsync.
+pub struct LaptopBattery;
+
+impl PowerSource for LaptopBattery {
+    fn state(&mut self) -> Result<PowerState, Error> {
+        // Some laptops report "unknown" when full, so only discharging counts
+        // as an outage.
+        match core::battery_present()? {
+            battery::State::Discharging => Ok(PowerState::Battery),
+            _ => Ok(PowerState::Mains),
+        }
+    }
+}
+
+// Reports each state for its duration, counted from the first reading of it,
+// and the last one for ever. Every state is read at least once. Used to drill
+// an outage without touching the battery.
+pub struct Scripted {
+    steps: Vec<(PowerState, Duration)>,
+    since: Option<Instant>,
+}
+
+impl Scripted {
+    pub fn new(steps: impl IntoIterator<Item = (PowerState, Duration)>) -> Self {
+        Scripted {
+            steps: steps.into_iter().collect(),
+            since: None,
+        }
+    }
+}
+
+impl PowerSource for Scripted {
+    fn state(&mut self) -> Result<PowerState, Error> {
+        let over = match (self.steps.first(), self.since) {
+            (Some((_, length)), Some(since)) => since.elapsed() >= *length,
+            _ => false,
+        };
+        if over && self.steps.len() > 1 {
+            self.steps.remove(0);
+            self.since = None;
+        }
+        self.since.get_or_insert_with(Instant::now);
+        // Without any steps the power never goes out.
+        Ok(self
+            .steps
+            .first()
+            .map_or(PowerState::Mains, |(state, _)| *state))
+    }
+}
+
+// Clients are identified by their configured name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    PowerLost,
+    PowerRestored,
+    ClientOnline {
+        client: String,
+    },
+    ClientOffline {
+        client: String,
+    },
+    ActionSent {
+        client: String,
+        action: String,
+    },
+    ActionFailed {
+        client: String,
+        error: String,
+    },
+    // Held back by inhibitor locks or critical processes; sent again when
+    // they change.
+    ActionDeferred {
+        client: String,
+        blockers: Vec<String>,
+    },
+    WakeSent {
+        client: String,
+    },
+    WakeFailed {
+        client: String,
+        error: String,
+    },
+    // WOL was sent every time but the client never answered.
+    WakeGaveUp {
+        client: String,
+        attempts: u32,
+    },
+    PostWakeFailed {
+        client: String,
+        error: String,
+    },
+    // New clients and policy were applied, outage state was kept.
+    Reconfigured,
+}
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    // Time between two power readings.
+    pub poll_interval: Duration,
+    // How long the power has to stay out before clients are acted upon.
+    pub grace_period: Duration,
+    // Send WOL to clients that have `wake` enabled once power returns.
+    pub wake_on_restore: bool,
+    // Which of those clients are woken.
+    pub wake: WakePolicy,
+    // Pause between two WOL packets, so not everything powers up at once.
+    pub wake_stagger: Duration,
+    // How long a client that has to go down first may keep answering before
+    // the next ones are acted upon anyway.
+    pub shutdown_timeout: Duration,
+    // How far into an outage actions may wait for inhibitor locks and
+    // critical processes on a client.
+    pub defer_limit: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WakePolicy {
+    // Only clients that upsync sent a popup or power action to.
+    #[default]
+    Slept,
+    // Also clients that were running when the outage began, e.g. ones that
+    // lost power because they aren't on the UPS.
+    Running,
+    // Every client that is offline once power returns.
+    Always,
+}
+
+impl WakePolicy {
+    pub fn allows(self, state: Option<&ClientState>) -> bool {
+        // A popup counts as slept: upsync can't tell what the user picked. So
+        // does an action that is still being sent.
+        let slept = state.is_some_and(|state| match (&state.action, &state.error) {
+            (Some(action), _) => !action.is_empty(),
+            (None, None) => state.acted_at.is_some(),
+            (None, Some(_)) => false,
+        });
+        let running = state.is_some_and(|state| state.online_before == Some(true));
+        match self {
+            WakePolicy::Slept => slept,
+            WakePolicy::Running => slept || running,
+            WakePolicy::Always => true,
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            poll_interval: Duration::from_secs(5),
+            grace_period: Duration::from_secs(5),
+            wake_on_restore: true,
+            wake: WakePolicy::default(),
+            wake_stagger: Duration::from_secs(10),
+            shutdown_timeout: Duration::from_secs(180),
+            defer_limit: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MonitorBuilder {
+    power: Option<Box<dyn PowerSource>>,
+    clients: Vec<ClientConfig>,
+    policy: Policy,
+    state_file: Option<PathBuf>,
+}
+
+impl MonitorBuilder {
+    pub fn power_source(mut self, power: impl PowerSource + 'static) -> Self {
+        self.power = Some(Box::new(power));
+        self
+    }
+
+    pub fn client(mut self, client: ClientConfig) -> Self {
+        self.clients.push(client);
+        self
+    }
+
+    pub fn clients(mut self, clients: impl IntoIterator<Item = ClientConfig>) -> Self {
+        self.clients.extend(clients);
+        self
+    }
+
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // Saves the outage state after every step and resumes from it on start.
+    // Without one the state only lives in memory.
+    pub fn state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    // Defaults to the laptop battery when no power source was given.
+    pub fn build(self) -> Result<Monitor, Error> {
+        if self.clients.is_empty() {
+            return Err(Error::Config("no clients configured".to_string()));
+        }
+
+        let state = match &self.state_file {
+            Some(path) => OutageState::load(path),
+            None => OutageState::default(),
+        };
+        let (outcome_sender, outcomes) = mpsc::channel();
+        let (check_sender, checks) = mpsc::channel();
+        let (report_sender, reports) = mpsc::channel();
+
+        Ok(Monitor {
+            power: self.power.unwrap_or_else(|| Box::new(LaptopBattery)),
+            clients: self.clients,
+            policy: self.policy,
+            state,
+            state_file: self.state_file,
+            reachable: HashMap::new(),
+            deferred: HashMap::new(),
+            subscribers: Vec::new(),
+            outcomes,
+            outcome_sender,
+            checking: HashMap::new(),
+            checks,
+            check_sender,
+            reports,
+            report_sender,
+            status: client::status,
+            wol: client::send_wol,
+            restore: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            update: Arc::new(Mutex::new(None)),
+        })
+    }
+}
+
+pub struct Monitor {
+    power: Box<dyn PowerSource>,
+    clients: Vec<ClientConfig>,
+    policy: Policy,
+    state: OutageState,
+    state_file: Option<PathBuf>,
+    // Last known reachability by client name, to only report changes.
+    reachable: HashMap<String, bool>,
+    // What last held back each client, to only report changes.
+    deferred: HashMap<String, Vec<String>>,
+    subscribers: Vec<Sender<Event>>,
+    // Actions run in their own thread, so waiting for one client to go down
+    // holds up neither the others nor the power readings.
+    outcomes: Receiver<Outcome>,
+    outcome_sender: Sender<Outcome>,
+    // Blocker checks are SSH sessions too, so they run in their own thread
+    // as well. By client name: None while running, then what it found.
+    checking: HashMap<String, Option<Vec<String>>>,
+    checks: Receiver<Check>,
+    check_sender: Sender<Check>,
+    // Events from other background work, emitted by the main loop.
+    reports: Receiver<Event>,
+    report_sender: Sender<Event>,
+    // How clients are probed and woken; faked in tests.
+    status: fn(&ClientConfig) -> bool,
+    wol: fn(&ClientConfig) -> Result<(), Error>,
+    // Set while clients are being woken.
+    restore: Option<Restore>,
+    stop: Arc<AtomicBool>,
+    update: Arc<Mutex<Option<Update>>>,
+}
+
+type Update = (Vec<ClientConfig>, Policy);
+
+// The clients still to be woken after an outage, by name so a reload in
+// between doesn't mix them up.
+struct Restore {
+    // In wake order.
+    queue: Vec<Queued>,
+    // Sent WOL, not yet seen back online.
+    pending: Vec<Waking>,
+    // When the next WOL may go out, for wake_stagger.
+    next_wake: Instant,
+}
+
+struct Queued {
+    name: String,
+    // Until when its wake_after dependencies may take to answer.
+    dependencies_until: Option<Instant>,
+    // Set once the client was found offline, default_delay ahead.
+    wol_at: Option<Instant>,
+}
+
+struct Waking {
+    name: String,
+    // WOL packets sent so far.
+    attempt: u32,
+    wait: Duration,
+    deadline: Instant,
+}
+
+impl Waking {
+    fn new(client: &ClientConfig, now: Instant) -> Waking {
+        let wait = Duration::from_secs(client.wake_timeout as u64);
+        Waking {
+            name: client.name.clone(),
+            attempt: 1,
+            wait,
+            deadline: now + wait,
+        }
+    }
+}
+
+// Client name and what was sent, or why it failed.
+type Outcome = (String, Result<String, String>);
+
+type Check = (String, Vec<String>);
+
+// Stops or reconfigures a running monitor from another thread.
+#[derive(Clone)]
+pub struct MonitorHandle {
+    stop: Arc<AtomicBool>,
+    update: Arc<Mutex<Option<Update>>>,
+}
+
+impl MonitorHandle {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    // Replaces the clients and policy before the next power reading. Both are
+    // swapped together; the outage state is kept by client name, so what was
+    // already done for a client isn't repeated.
+    pub fn reconfigure(&self, clients: Vec<ClientConfig>, policy: Policy) -> Result<(), Error> {
+        if clients.is_empty() {
+            return Err(Error::Config("no clients configured".to_string()));
+        }
+        // A poisoned lock only means the monitor thread panicked.
+        let mut update = self.update.lock().unwrap_or_else(|err| err.into_inner());
+        *update = Some((clients, policy));
+        Ok(())
+    }
+}
+
+impl Monitor {
+    pub fn builder() -> MonitorBuilder {
+        MonitorBuilder::default()
+    }
+
+    // Every subscriber receives every event emitted after it subscribed.
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn handle(&self) -> MonitorHandle {
+        MonitorHandle {
+            stop: self.stop.clone(),
+            update: self.update.clone(),
+        }
+    }
+
+    pub fn clients(&self) -> &[ClientConfig] {
+        &self.clients
+    }
+
+    pub fn state(&self) -> &OutageState {
+        &self.state
+    }
+
+    // Blocks until stopped through a MonitorHandle.
+    pub fn run(&mut self) -> Result<(), Error> {
+        for client in &self.clients {
+            let online = (self.status)(client);
+            self.emit(client_event(client, online));
+            self.reachable.insert(client.name.clone(), online);
+            // Asked now so an outage doesn't have to wait for it.
+            if online {
+                client::capabilities(client);
+            }
+        }
+
+        match self.state.phase {
+            Phase::Mains => {}
+            Phase::OnBattery => info!("Resuming the outage that started before the restart"),
+            Phase::Restoring => {
+                info!("Resuming waking the clients after the restart");
+                self.start_restore();
+            }
+        }
+
+        let mut log = true;
+
+        while !self.stop.load(Ordering::SeqCst) {
+            trace!("Main loop!");
+            thread::sleep(self.policy.poll_interval);
+            self.apply_update();
+            self.record_outcomes();
+
+            let state = match self.power.state() {
+                Ok(state) => state,
+                Err(err) => {
+                    error!("Error: {}", err);
+                    continue;
+                }
+            };
+
+            match (state, self.state.phase == Phase::OnBattery) {
+                (PowerState::Battery, false) => {
+                    warn!("device is discharging.");
+                    thread::sleep(self.policy.grace_period);
+
+                    match self.power.state() {
+                        Ok(PowerState::Battery) => {}
+                        Ok(PowerState::Mains) => {
+                            info!("power is back.");
+                            continue;
+                        }
+                        Err(err) => {
+                            error!("Unable to read battery status: {}", err);
+                            continue;
+                        }
+                    }
+
+                    info!("Device is discharging. Waiting for power to return.");
+                    if self.restore.take().is_some() {
+                        warn!("Power was lost again, no longer waking the clients");
+                    }
+                    log = true;
+                    self.checking.clear();
+                    self.state.enter(Phase::OnBattery);
+                    self.emit(Event::PowerLost);
+                    self.record_online();
+                }
+                (PowerState::Battery, true) => debug!("Device is discharging"),
+                (PowerState::Mains, true) => {
+                    info!("Device is charging and power is back");
+                    self.enter(Phase::Restoring);
+                    self.emit(Event::PowerRestored);
+                    self.start_restore();
+                }
+                (PowerState::Mains, false) => {
+                    debug!("Device is charging: {:?}", state);
+                    if log {
+                        info!("Device is charging");
+                        log = false
+                    }
+                }
+            }
+
+            match self.state.phase {
+                Phase::OnBattery => self.act_on_clients(),
+                Phase::Restoring => self.step_restore(),
+                Phase::Mains => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    // What was running before anything was sent, for WakePolicy::Running.
+    fn record_online(&mut self) {
+        for i in 0..self.clients.len() {
+            let client = &self.clients[i];
+            let online = (self.status)(client);
+            if self.reachable.insert(client.name.clone(), online) != Some(online) {
+                self.emit(client_event(client, online));
+            }
+            let name = client.name.clone();
+            self.state.client(&name).online_before = Some(online);
+        }
+        self.save_state();
+    }
+
+    fn enter(&mut self, phase: Phase) {
+        self.state.enter(phase);
+        self.save_state();
+    }
+
+    fn save_state(&self) {
+        if let Some(path) = &self.state_file {
+            if let Err(err) = self.state.save(path) {
+                error!("Could not save state to {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    fn apply_update(&mut self) {
+        let update = self
+            .update
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+        let Some((clients, policy)) = update else {
+            return;
+        };
+
+        info!("Applied new config with {} client(s)", clients.len());
+        self.clients = clients;
+        self.policy = policy;
+        self.emit(Event::Reconfigured);
+    }
+
+    // Clients that are offline when the outage starts are acted upon as soon
+    // as they come online. A client is held back while a client it has to
+    // follow, by shutdown_priority or shutdown_after, still answers, until
+    // shutdown_timeout has passed since that one was acted upon.
+    fn act_on_clients(&mut self) {
+        for i in 0..self.clients.len() {
+            let client = &self.clients[i];
+            let online = (self.status)(client);
+            if self.reachable.insert(client.name.clone(), online) != Some(online) {
+                self.emit(client_event(client, online));
+            }
+        }
+
+        let order = shutdown_order(&self.clients).unwrap_or_else(|err| {
+            warn!("{}, acting in config order", err);
+            (0..self.clients.len()).collect()
+        });
+        for i in order {
+            let client = &self.clients[i];
+            if self.state.acted(&client.name) {
+                continue;
+            }
+            if !self
+                .reachable
+                .get(&client.name)
+                .copied()
+                .unwrap_or_default()
+            {
+                debug!("client {} is offline", client.name);
+                continue;
+            }
+
+            let (waiting, overdue) = self.shutdown_blockers(i);
+            if !waiting.is_empty() {
+                debug!(
+                    "{} waits for {} to go down",
+                    client.name,
+                    waiting.join(", ")
+                );
+                continue;
+            }
+            if !overdue.is_empty() {
+                warn!(
+                    "{} still answers after {}s, acting on {} anyway",
+                    overdue.join(", "),
+                    self.policy.shutdown_timeout.as_secs(),
+                    client.name
+                );
+            }
+
+            // The client is looked at again once the check is done.
+            let blockers = match self.checking.remove(&client.name) {
+                Some(Some(blockers)) => blockers,
+                Some(None) => {
+                    self.checking.insert(client.name.clone(), None);
+                    continue;
+                }
+                None => {
+                    let client = client.clone();
+                    let sender = self.check_sender.clone();
+                    self.checking.insert(client.name.clone(), None);
+                    thread::spawn(move || {
+                        let blockers = client::blockers(&client);
+                        let _ = sender.send((client.name, blockers));
+                    });
+                    continue;
+                }
+            };
+            if !blockers.is_empty() {
+                let elapsed = state::now().saturating_sub(self.state.since);
+                if elapsed < self.policy.defer_limit.as_secs() {
+                    if self.deferred.get(&client.name) != Some(&blockers) {
+                        info!("Holding back {}: {}", client.name, blockers.join("; "));
+                        self.deferred.insert(client.name.clone(), blockers.clone());
+                        self.emit(Event::ActionDeferred {
+                            client: client.name.clone(),
+                            blockers,
+                        });
+                    }
+                    continue;
+                }
+                warn!(
+                    "{} is still busy after {}s, acting anyway: {}",
+                    client.name,
+                    elapsed,
+                    blockers.join("; ")
+                );
+            }
+
+            // Saved before sending so a restart in between can't send twice.
+            let name = client.name.clone();
+            self.state.client(&name).acted_at = Some(state::now());
+            self.save_state();
+
+            debug!("Opening popup in client {}", name);
+            let client = self.clients[i].clone();
+            let sender = self.outcome_sender.clone();
+            thread::spawn(move || {
+                let result = client::send_device_to(&client, &blockers);
+                // The monitor may be gone already when stopped meanwhile.
+                let _ = sender.send((client.name, result.map_err(|err| err.to_string())));
+            });
+        }
+    }
+
+    // Saves and reports the actions that finished since the last call, keeps
+    // the blockers found meanwhile for act_on_clients and passes on what the
+    // other background work reported.
+    fn record_outcomes(&mut self) {
+        while let Ok((name, blockers)) = self.checks.try_recv() {
+            // Cleared when another outage began meanwhile.
+            if let Some(check) = self.checking.get_mut(&name) {
+                *check = Some(blockers);
+            }
+        }
+        while let Ok(event) = self.reports.try_recv() {
+            self.emit(event);
+        }
+        while let Ok((name, result)) = self.outcomes.try_recv() {
+            // Cleared when another outage began meanwhile.
+            let Some(entry) = self.state.clients.get_mut(&name) else {
+                continue;
+            };
+            entry.action = result.as_ref().ok().cloned();
+            entry.error = result.as_ref().err().cloned();
+            self.save_state();
+
+            match result {
+                Ok(action) => self.emit(Event::ActionSent {
+                    client: name,
+                    action,
+                }),
+                Err(error) => self.emit(Event::ActionFailed {
+                    client: name,
+                    error,
+                }),
+            }
+        }
+    }
+
+    // The online clients that have to go down before client i, split into
+    // those still within shutdown_timeout and those past it.
+    fn shutdown_blockers(&self, i: usize) -> (Vec<String>, Vec<String>) {
+        let client = &self.clients[i];
+        let mut waiting = Vec::new();
+        let mut overdue = Vec::new();
+
+        for other in &self.clients {
+            let first = other.shutdown_priority < client.shutdown_priority
+                || client.shutdown_after.contains(&other.name);
+            let online = self.reachable.get(&other.name).copied().unwrap_or_default();
+            if other.name == client.name || !first || !online {
+                continue;
+            }
+
+            let acted_at = self
+                .state
+                .clients
+                .get(&other.name)
+                .and_then(|state| state.acted_at);
+            match acted_at {
+                Some(at)
+                    if state::now().saturating_sub(at)
+                        >= self.policy.shutdown_timeout.as_secs() =>
+                {
+                    overdue.push(other.name.clone())
+                }
+                _ => waiting.push(other.name.clone()),
+            }
+        }
+        (waiting, overdue)
+    }
+
+    // Starts waking the clients once power is back, skipping those already
+    // woken before a restart. The wakes are then driven by step_restore on
+    // every power reading, so a new outage is noticed and ends them.
+    fn start_restore(&mut self) {
+        for client in self
+            .clients
+            .iter()
+            .filter(|client| !client.agent.is_empty())
+        {
+            let popup = self
+                .state
+                .clients
+                .get(&client.name)
+                .is_some_and(|state| state.action.as_deref() == Some("popup"));
+            agent::power_restored(client, popup);
+        }
+        if !self.policy.wake_on_restore {
+            self.enter(Phase::Mains);
+            return;
+        }
+
+        let order = wake_order(&self.clients).unwrap_or_else(|err| {
+            warn!("{}, waking in config order", err);
+            (0..self.clients.len()).collect()
+        });
+        let mut queue = Vec::new();
+        for i in order {
+            let client = &self.clients[i];
+            let state = self.state.clients.get(&client.name);
+            let woken = state.is_some_and(|state| state.woken_at.is_some());
+            if !client.wake || woken {
+                continue;
+            }
+            if !self.policy.wake.allows(state) {
+                info!("Not waking {}, upsync didn't put it to sleep", client.name);
+                continue;
+            }
+            queue.push(Queued {
+                name: client.name.clone(),
+                dependencies_until: None,
+                wol_at: None,
+            });
+        }
+
+        self.restore = Some(Restore {
+            queue,
+            pending: Vec::new(),
+            next_wake: Instant::now(),
+        });
+        self.step_restore();
+    }
+
+    // Checks on the clients that were sent WOL and wakes the next ones in
+    // wake order, wake_stagger apart. A client waits for its wake_after
+    // dependencies to answer. Never blocks for long.
+    fn step_restore(&mut self) {
+        let Some(mut restore) = self.restore.take() else {
+            return;
+        };
+
+        restore.pending = std::mem::take(&mut restore.pending)
+            .into_iter()
+            .filter_map(|waking| self.verify_wake(waking))
+            .collect();
+
+        while let Some(queued) = restore.queue.first_mut() {
+            // Dropped from the config meanwhile.
+            let Some(client) = self.client_by_name(&queued.name) else {
+                restore.queue.remove(0);
+                continue;
+            };
+            if let Some(dependency) = client
+                .wake_after
+                .iter()
+                .find(|dependency| restore.pending.iter().any(|w| &w.name == *dependency))
+            {
+                debug!("{} waits for {} to come back", client.name, dependency);
+                break;
+            }
+            let now = Instant::now();
+            if now < restore.next_wake {
+                break;
+            }
+
+            if queued.wol_at.is_none() {
+                let timeout = Duration::from_secs(client.wake_timeout as u64);
+                let until = *queued.dependencies_until.get_or_insert(now + timeout);
+                let offline = self.offline_dependencies(&client);
+                if !offline.is_empty() {
+                    if now < until {
+                        debug!("{} waits for {} to answer", client.name, offline.join(", "));
+                        break;
+                    }
+                    warn!(
+                        "{} is not reachable, waking {} anyway",
+                        offline.join(", "),
+                        client.name
+                    );
+                }
+                if (self.status)(&client) {
+                    info!("{} is online, skipping WOL", client.name);
+                    self.emit(Event::ClientOnline {
+                        client: client.name.clone(),
+                    });
+                    self.woken(&client.name);
+                    restore.queue.remove(0);
+                    continue;
+                }
+                let delay = Duration::from_secs(client.default_delay as u64);
+                info!(
+                    "{} is offline, sending WOL in {} seconds",
+                    client.name,
+                    delay.as_secs()
+                );
+                queued.wol_at = Some(now + delay);
+            }
+            if queued.wol_at.is_some_and(|at| now < at) {
+                break;
+            }
+
+            restore.queue.remove(0);
+            match self.send_wake(&client) {
+                true => {
+                    restore.next_wake = now + self.policy.wake_stagger;
+                    restore.pending.push(Waking::new(&client, now));
+                }
+                false => self.woken(&client.name),
+            }
+        }
+
+        if restore.queue.is_empty() && restore.pending.is_empty() {
+            self.enter(Phase::Mains);
+        } else {
+            self.restore = Some(restore);
+        }
+    }
+
+    fn client_by_name(&self, name: &str) -> Option<ClientConfig> {
+        self.clients.iter().find(|c| c.name == name).cloned()
+    }
+
+    fn woken(&mut self, name: &str) {
+        self.state.client(name).woken_at = Some(state::now());
+        self.save_state();
+    }
+
+    // The wake_after dependencies that don't answer. Those that were never
+    // put to sleep, e.g. a NAS, get the client's wake_timeout to come up.
+    fn offline_dependencies(&self, client: &ClientConfig) -> Vec<String> {
+        client
+            .wake_after
+            .iter()
+            .filter_map(|dependency| self.client_by_name(dependency))
+            .filter(|dependency| !(self.status)(dependency))
+            .map(|dependency| dependency.name)
+            .collect()
+    }
+
+    // Sends WOL. Returns false when the client needs no verification, because
+    // WOL failed.
+    fn send_wake(&self, client: &ClientConfig) -> bool {
+        let name = || client.name.clone();
+        match (self.wol)(client) {
+            Ok(()) => {
+                self.emit(Event::WakeSent { client: name() });
+                true
+            }
+            Err(err) => {
+                self.emit(Event::WakeFailed {
+                    client: name(),
+                    error: err.to_string(),
+                });
+                false
+            }
+        }
+    }
+
+    // Checks whether a woken client answers yet. Past its wait, WOL is sent
+    // again with the wait doubled until wake_retries is used up. Returns the
+    // client while it still has to be waited for.
+    fn verify_wake(&mut self, mut waking: Waking) -> Option<Waking> {
+        // Dropped from the config meanwhile.
+        let client = self.client_by_name(&waking.name)?;
+        let name = || client.name.clone();
+        if (self.status)(&client) {
+            info!("{} is back online", client.name);
+            self.emit(Event::ClientOnline { client: name() });
+            self.post_wake(&client);
+            self.woken(&client.name);
+            return None;
+        }
+        let now = Instant::now();
+        if now < waking.deadline {
+            return Some(waking);
+        }
+
+        let attempts = client.wake_retries + 1;
+        if waking.attempt < attempts {
+            warn!(
+                "{} didn't come back within {}s, sending WOL again ({}/{})",
+                client.name,
+                waking.wait.as_secs(),
+                waking.attempt,
+                client.wake_retries
+            );
+            match (self.wol)(&client) {
+                Ok(()) => self.emit(Event::WakeSent { client: name() }),
+                Err(err) => self.emit(Event::WakeFailed {
+                    client: name(),
+                    error: err.to_string(),
+                }),
+            }
+            waking.attempt += 1;
+            waking.wait *= 2;
+            waking.deadline = now + waking.wait;
+            return Some(waking);
+        }
+
+        error!(
+            "{} never came back after {} WOL attempt(s)",
+            client.name, attempts
+        );
+        self.emit(Event::WakeGaveUp {
+            client: name(),
+            attempts,
+        });
+        self.woken(&client.name);
+        None
+    }
+
+    // Runs post_wake_command in the background, it may take a while.
+    fn post_wake(&self, client: &ClientConfig) {
+        if client.post_wake_command.is_empty() {
+            return;
+        }
+        let client = client.clone();
+        let sender = self.report_sender.clone();
+        thread::spawn(move || {
+            match client::connect(&client)
+                .and_then(|remote| remote.output(&client.post_wake_command))
+            {
+                Ok(output) => debug!("post-wake command on {}: {}", client.name, output),
+                Err(err) => {
+                    error!("post-wake command on {} failed: {}", client.name, err);
+                    let _ = sender.send(Event::PostWakeFailed {
+                        client: client.name,
+                        error: err.to_string(),
+                    });
+                }
+            }
+        });
+    }
+
+    fn emit(&self, event: Event) {
+        trace!("event: {:?}", event);
+        for subscriber in &self.subscribers {
+            // A dropped receiver only means nobody is listening anymore.
+            let _ = subscriber.send(event.clone());
+        }
+    }
+}
+
+// Indexes of the clients in the order they are woken: every client after the
+// clients in its wake_after, otherwise by wake_priority (lowest first) and then
+// config order. Fails on a dependency cycle.
+pub(crate) fn wake_order(clients: &[ClientConfig]) -> Result<Vec<usize>, String> {
+    ordered(clients, "wake_after", |client| {
+        (client.wake_priority, &client.wake_after)
+    })
+}
+
+// Like wake_order, for shutdown_priority and shutdown_after.
+pub(crate) fn shutdown_order(clients: &[ClientConfig]) -> Result<Vec<usize>, String> {
+    ordered(clients, "shutdown_after", |client| {
+        (client.shutdown_priority, &client.shutdown_after)
+    })
+}
+
+fn ordered(
+    clients: &[ClientConfig],
+    field: &str,
+    key: impl Fn(&ClientConfig) -> (u32, &Vec<String>),
+) -> Result<Vec<usize>, String> {
+    let mut order: Vec<usize> = Vec::with_capacity(clients.len());
+    let mut left: Vec<usize> = (0..clients.len()).collect();
+    left.sort_by_key(|&i| key(&clients[i]).0);
+
+    while !left.is_empty() {
+        let ready = left.iter().position(|&i| {
+            key(&clients[i]).1.iter().all(|dependency| {
+                !left
+                    .iter()
+                    .any(|&j| j != i && &clients[j].name == dependency)
+            })
+        });
+        match ready {
+            Some(at) => order.push(left.remove(at)),
+            None => {
+                let names: Vec<&str> = left.iter().map(|&i| clients[i].name.as_str()).collect();
+                return Err(format!(
+                    "{} has a cycle between {}",
+                    field,
+                    names.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(order)
+}
+
+fn client_event(client: &ClientConfig, online: bool) -> Event {
+    let client = client.name.clone();
+    match online {
+        true => Event::ClientOnline { client },
+        false => Event::ClientOffline { client },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn wakes_by_policy() {
+        let slept = ClientState {
+            online_before: Some(true),
+            action: Some("suspend".to_string()),
+            ..ClientState::default()
+        };
+        let failed = ClientState {
+            online_before: Some(true),
+            ..ClientState::default()
+        };
+        let was_off = ClientState {
+            online_before: Some(false),
+            ..ClientState::default()
+        };
+
+        let sending = ClientState {
+            online_before: Some(true),
+            acted_at: Some(state::now()),
+            ..ClientState::default()
+        };
+
+        assert!(WakePolicy::Slept.allows(Some(&slept)));
+        assert!(WakePolicy::Slept.allows(Some(&sending)));
+        assert!(!WakePolicy::Slept.allows(Some(&failed)));
+        assert!(WakePolicy::Running.allows(Some(&failed)));
+        assert!(!WakePolicy::Running.allows(Some(&was_off)));
+        assert!(!WakePolicy::Running.allows(None));
+        assert!(WakePolicy::Always.allows(Some(&was_off)));
+    }
+
+    fn client(name: &str, priority: u32, after: &[&str]) -> ClientConfig {
+        ClientConfig {
+            name: name.to_string(),
+            wake_priority: priority,
+            wake_after: after.iter().map(ToString::to_string).collect(),
+            ..ClientConfig::default()
+        }
+    }
+
+    #[test]
+    fn orders_wakes_by_dependency_then_priority() {
+        let clients = [
+            client("desktop", 0, &["nas"]),
+            client("printer", 5, &[]),
+            client("nas", 9, &[]),
+            client("laptop", 0, &[]),
+        ];
+        assert_eq!(wake_order(&clients).unwrap(), vec![3, 1, 2, 0]);
+
+        let cycle = [client("a", 0, &["b"]), client("b", 0, &["a"])];
+        assert!(wake_order(&cycle).is_err());
+    }
+
+    #[test]
+    fn reads_scripted_states_for_their_duration() {
+        let mut power = Scripted::new([
+            (PowerState::Battery, Duration::from_millis(50)),
+            (PowerState::Mains, Duration::ZERO),
+        ]);
+        assert_eq!(power.state().unwrap(), PowerState::Battery);
+        assert_eq!(power.state().unwrap(), PowerState::Battery);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(power.state().unwrap(), PowerState::Mains);
+        assert_eq!(power.state().unwrap(), PowerState::Mains);
+        assert_eq!(Scripted::new([]).state().unwrap(), PowerState::Mains);
+    }
+
+    #[test]
+    fn stops_waking_when_power_is_lost_again() {
+        let mut monitor = Monitor::builder()
+            .power_source(Scripted::new([
+                (PowerState::Mains, Duration::ZERO),
+                (PowerState::Battery, Duration::ZERO),
+            ]))
+            .client(ClientConfig {
+                // Nothing answers there, and WOL would only go out in an hour.
+                ip: "127.0.0.1:1".to_string(),
+                wake: true,
+                default_delay: 3600,
+                ..client("desktop", 0, &[])
+            })
+            .policy(Policy {
+                poll_interval: Duration::from_millis(10),
+                grace_period: Duration::from_millis(10),
+                ..Policy::default()
+            })
+            .build()
+            .unwrap();
+        monitor.state.enter(Phase::Restoring);
+        monitor.state.client("desktop").action = Some("suspend".to_string());
+        let events = monitor.subscribe();
+        let handle = monitor.handle();
+        let running = thread::spawn(move || {
+            monitor.run().unwrap();
+            monitor
+        });
+
+        let lost = (0..100)
+            .any(|_| events.recv_timeout(Duration::from_millis(50)) == Ok(Event::PowerLost));
+        handle.stop();
+        let monitor = running.join().unwrap();
+        assert!(lost);
+        assert_eq!(monitor.state.phase, Phase::OnBattery);
+        assert!(monitor.restore.is_none());
+    }
+
+    #[test]
+    fn resends_wol_with_backoff_then_gives_up() {
+        let mut monitor = Monitor::builder()
+            .power_source(Scripted::new([(PowerState::Mains, Duration::ZERO)]))
+            .client(ClientConfig {
+                wake: true,
+                default_delay: 0,
+                wake_timeout: 1,
+                wake_retries: 2,
+                ..client("desktop", 0, &[])
+            })
+            .policy(Policy {
+                poll_interval: Duration::from_millis(10),
+                ..Policy::default()
+            })
+            .build()
+            .unwrap();
+        // Never comes back, whatever is sent.
+        monitor.status = |_| false;
+        monitor.wol = |_| Ok(());
+        monitor.state.enter(Phase::Restoring);
+        monitor.state.client("desktop").action = Some("suspend".to_string());
+        let events = monitor.subscribe();
+        let handle = monitor.handle();
+        let start = Instant::now();
+        let running = thread::spawn(move || {
+            monitor.run().unwrap();
+            monitor
+        });
+
+        let mut sent = 0;
+        let mut gave_up = None;
+        while let Ok(event) = events.recv_timeout(Duration::from_secs(15)) {
+            match event {
+                Event::WakeSent { .. } => sent += 1,
+                Event::WakeGaveUp { attempts, .. } => {
+                    gave_up = Some(attempts);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let elapsed = start.elapsed();
+        handle.stop();
+        let monitor = running.join().unwrap();
+
+        assert_eq!(sent, 3);
+        assert_eq!(gave_up, Some(3));
+        // Waited 1s, then 2s, then 4s.
+        assert!(elapsed >= Duration::from_secs(7));
+        assert!(elapsed < Duration::from_secs(10));
+        assert_eq!(monitor.state.phase, Phase::Mains);
+        assert!(monitor.state.clients["desktop"].woken_at.is_some());
+    }
+
+    #[test]
+    fn waits_for_dependencies_without_blocking() {
+        let mut monitor = Monitor::builder()
+            .client(ClientConfig {
+                ip: "127.0.0.1:1".to_string(),
+                ..client("nas", 0, &[])
+            })
+            .client(ClientConfig {
+                ip: "127.0.0.1:1".to_string(),
+                wake: true,
+                wake_timeout: 3600,
+                ..client("desktop", 0, &["nas"])
+            })
+            .build()
+            .unwrap();
+        monitor.state.enter(Phase::Restoring);
+        monitor.state.client("desktop").action = Some("suspend".to_string());
+
+        let start = Instant::now();
+        monitor.start_restore();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        let queued = &monitor.restore.as_ref().unwrap().queue[0];
+        assert_eq!(queued.name, "desktop");
+        assert!(queued.dependencies_until.is_some());
+        assert!(queued.wol_at.is_none());
+    }
+
+    #[test]
+    fn checks_blockers_in_the_background() {
+        // Accepts connections but never says anything, like a client that
+        // stalls while going down.
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut monitor = Monitor::builder()
+            .client(ClientConfig {
+                ip: silent.local_addr().unwrap().to_string(),
+                ..client("desktop", 0, &[])
+            })
+            .build()
+            .unwrap();
+        monitor.state.enter(Phase::OnBattery);
+
+        let start = Instant::now();
+        monitor.act_on_clients();
+        monitor.act_on_clients();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(monitor.checking.get("desktop"), Some(&None));
+        assert!(!monitor.state.acted("desktop"));
+
+        monitor
+            .check_sender
+            .send(("desktop".to_string(), Vec::new()))
+            .unwrap();
+        monitor.record_outcomes();
+        monitor.act_on_clients();
+        assert!(monitor.state.acted("desktop"));
+        assert!(monitor.checking.is_empty());
+    }
+
+    #[test]
+    fn applies_a_new_config_mid_outage() {
+        let desktop = ClientConfig {
+            ip: "127.0.0.1:1".to_string(),
+            ..client("desktop", 0, &[])
+        };
+        let mut monitor = Monitor::builder().client(desktop.clone()).build().unwrap();
+        monitor.status = |_| true;
+        let events = monitor.subscribe();
+        let handle = monitor.handle();
+        monitor.state.enter(Phase::OnBattery);
+        monitor.state.since = state::now() - 100;
+        let busy = || Some(vec!["restic: backup".to_string()]);
+
+        // Within the default defer_limit of 300s the busy client waits.
+        monitor.checking.insert("desktop".to_string(), busy());
+        monitor.act_on_clients();
+        assert!(!monitor.state.acted("desktop"));
+
+        assert!(handle.reconfigure(Vec::new(), Policy::default()).is_err());
+        let laptop = ClientConfig {
+            ip: "127.0.0.1:1".to_string(),
+            ..client("laptop", 0, &[])
+        };
+        let policy = Policy {
+            defer_limit: Duration::from_secs(60),
+            ..Policy::default()
+        };
+        handle.reconfigure(vec![desktop, laptop], policy).unwrap();
+        monitor.apply_update();
+        assert_eq!(monitor.state.phase, Phase::OnBattery);
+        assert_eq!(monitor.clients().len(), 2);
+
+        // Past the new limit it is acted upon, and the new client is checked
+        // like the others.
+        monitor.checking.insert("desktop".to_string(), busy());
+        monitor.act_on_clients();
+        assert!(monitor.state.acted("desktop"));
+        assert_eq!(monitor.checking.get("laptop"), Some(&None));
+
+        let events: Vec<Event> = events.try_iter().collect();
+        assert!(events.contains(&Event::ActionDeferred {
+            client: "desktop".to_string(),
+            blockers: vec!["restic: backup".to_string()],
+        }));
+        assert!(events.contains(&Event::Reconfigured));
+    }
+
+    #[test]
+    fn forgets_removed_clients() {
+        let nas = ClientConfig {
+            ip: "127.0.0.1:1".to_string(),
+            ..client("nas", 0, &[])
+        };
+        let mut monitor = Monitor::builder()
+            .client(nas.clone())
+            .client(ClientConfig {
+                ip: "127.0.0.1:1".to_string(),
+                wake: true,
+                default_delay: 3600,
+                ..client("desktop", 0, &[])
+            })
+            .build()
+            .unwrap();
+        monitor.status = |_| false;
+        monitor.state.enter(Phase::Restoring);
+        monitor.state.client("desktop").action = Some("suspend".to_string());
+        monitor.start_restore();
+        assert_eq!(monitor.restore.as_ref().unwrap().queue.len(), 1);
+
+        monitor
+            .handle()
+            .reconfigure(vec![nas], Policy::default())
+            .unwrap();
+        monitor.apply_update();
+        monitor.step_restore();
+        assert!(monitor.restore.is_none());
+        assert_eq!(monitor.state.phase, Phase::Mains);
+        // What happened to it during the outage is kept.
+        assert!(monitor.state.clients.contains_key("desktop"));
+    }
+
+    #[test]
+    fn holds_shutdown_until_dependencies_are_down() {
+        let mut nas = client("nas", 0, &[]);
+        nas.shutdown_after = vec!["desktop".to_string()];
+        let mut monitor = Monitor::builder()
+            .client(client("desktop", 0, &[]))
+            .client(nas)
+            .client(ClientConfig {
+                shutdown_priority: 1,
+                ..client("printer", 0, &[])
+            })
+            .build()
+            .unwrap();
+        monitor.reachable.insert("desktop".to_string(), true);
+        monitor.reachable.insert("nas".to_string(), true);
+
+        assert_eq!(monitor.shutdown_blockers(0), (vec![], vec![]));
+        assert_eq!(monitor.shutdown_blockers(1).0, vec!["desktop"]);
+        assert_eq!(monitor.shutdown_blockers(2).0, vec!["desktop", "nas"]);
+
+        monitor.state.client("desktop").acted_at = Some(state::now() - 600);
+        assert_eq!(
+            monitor.shutdown_blockers(1),
+            (vec![], vec!["desktop".to_string()])
+        );
+
+        monitor.reachable.insert("desktop".to_string(), false);
+        assert_eq!(monitor.shutdown_blockers(1), (vec![], vec![]));
+    }
+}
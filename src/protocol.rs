@@ -0,0 +1,581 @@
+use crate::Error;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time;
+
+// What the server and `upsync agent` say to each other over TCP.
+//
+// Every frame is a 4 byte big-endian length followed by that many bytes. The
+// connection starts with a handshake of plain JSON frames, which proves to
+// both sides that the other knows the shared key without sending it:
+//
+//   server -> agent  Hello { version, nonce }
+//   agent -> server  Hello { version, nonce, proof }
+//   server -> agent  Proof { proof }
+//
+// The shared key is at least 32 random bytes written as hex, so the proofs are
+// no use for guessing it. HKDF turns it and both nonces into the proof keys and
+// one key per direction.
+// Every later frame is an Envelope sealed with ChaCha20-Poly1305, its nonce
+// the number of frames sent before in that direction. A frame that is
+// replayed, dropped, reordered or from an earlier connection fails to open.
+pub(crate) const VERSION: u32 = 2;
+
+// Nothing we send comes close; stops a peer from making us allocate.
+const MAX_FRAME: usize = 64 * 1024;
+
+// A peer that hasn't finished its side of the handshake after this long is
+// dropped, however slowly it keeps sending.
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+pub(crate) const MIN_KEY_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub(crate) enum Message {
+    // Power events, server to agent.
+    PowerLost,
+    Countdown {
+        default: String,
+        seconds: u32,
+        actions: Vec<String>,
+        blockers: String,
+    },
+    Cancel,
+    Restored,
+    // Commands: the server asks for a power state, the agent reports what
+    // the user picked and how running it went.
+    Command {
+        action: String,
+    },
+    Choice {
+        action: String,
+    },
+    Done {
+        action: String,
+        error: Option<String>,
+    },
+    // Every message but an Ack is acknowledged with the id it came in,
+    // which is how heartbeats are answered.
+    Ack {
+        id: u64,
+    },
+    Heartbeat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Envelope {
+    // Counts the frames sent in one direction, from 0.
+    pub(crate) id: u64,
+    pub(crate) message: Message,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Handshake {
+    Hello {
+        version: u32,
+        nonce: Vec<u8>,
+        #[serde(default)]
+        proof: Vec<u8>,
+    },
+    Proof {
+        proof: Vec<u8>,
+    },
+}
+
+// The sending half of a connection.
+pub(crate) struct Sender {
+    stream: TcpStream,
+    cipher: ChaCha20Poly1305,
+    sent: u64,
+}
+
+// The receiving half. Bytes of a frame that arrived before a timeout are kept
+// for the next call.
+pub(crate) struct Receiver {
+    stream: TcpStream,
+    cipher: ChaCha20Poly1305,
+    received: u64,
+    buffer: Vec<u8>,
+}
+
+impl Sender {
+    // Returns the id the peer acknowledges the message with.
+    pub(crate) fn send(&mut self, message: &Message) -> Result<u64, Error> {
+        let id = self.sent;
+        let envelope = Envelope {
+            id,
+            message: message.clone(),
+        };
+        let plain = serde_json::to_vec(&envelope).map_err(io::Error::from)?;
+        let sealed = self
+            .cipher
+            .encrypt(&nonce(id), plain.as_slice())
+            .map_err(|_| Error::Command("could not encrypt a message".to_string()))?;
+        write_frame(&mut self.stream, &sealed)?;
+        self.sent += 1;
+        Ok(id)
+    }
+}
+
+impl Receiver {
+    // How long recv waits before failing with a timeout, None for ever.
+    pub(crate) fn set_timeout(&self, timeout: Option<time::Duration>) -> Result<(), Error> {
+        self.stream.set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    pub(crate) fn recv(&mut self) -> Result<Envelope, Error> {
+        let sealed = read_frame(&mut self.stream, &mut self.buffer)?;
+        let plain = self
+            .cipher
+            .decrypt(&nonce(self.received), sealed.as_slice())
+            .map_err(|_| {
+                Error::Auth("a message was forged, replayed or arrived out of order".to_string())
+            })?;
+        let envelope: Envelope = serde_json::from_slice(&plain)
+            .map_err(|err| Error::Command(format!("invalid message: {}", err)))?;
+        if envelope.id != self.received {
+            return Err(Error::Auth(format!(
+                "expected message {}, got {}",
+                self.received, envelope.id
+            )));
+        }
+        self.received += 1;
+        Ok(envelope)
+    }
+}
+
+// Whether recv failed only because nothing arrived in time.
+pub(crate) fn timed_out(err: &Error) -> bool {
+    matches!(err, Error::Io(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+// Whether the peer hung up.
+pub(crate) fn closed(err: &Error) -> bool {
+    matches!(err, Error::Io(err) if err.kind() == ErrorKind::UnexpectedEof)
+}
+
+// The server's side of the handshake. Both sides leave what is left of the
+// handshake timeout set for the caller to replace.
+pub(crate) fn connect(stream: TcpStream, key: &str) -> Result<(Sender, Receiver), Error> {
+    let key = parse_key(key)?;
+    let mut stream = stream;
+    let deadline = time::Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut buffer = Vec::new();
+    let ours = random_nonce()?;
+    send_handshake(
+        &mut stream,
+        &Handshake::Hello {
+            version: VERSION,
+            nonce: ours.to_vec(),
+            proof: Vec::new(),
+        },
+    )?;
+
+    let (theirs, proof) = match recv_handshake(&mut stream, &mut buffer, deadline)? {
+        Handshake::Hello { version, .. } if version != VERSION => {
+            return Err(Error::Command(format!(
+                "the agent speaks protocol version {}, we speak {}",
+                version, VERSION
+            )))
+        }
+        Handshake::Hello { nonce, proof, .. } => (nonce, proof),
+        Handshake::Proof { .. } => return Err(unexpected()),
+    };
+    let secrets = Secrets::derive(&key, &ours, &theirs)?;
+    secrets.verify(&secrets.agent_proof, &proof)?;
+    send_handshake(
+        &mut stream,
+        &Handshake::Proof {
+            proof: secrets.prove(&secrets.server_proof)?,
+        },
+    )?;
+    split(stream, buffer, &secrets.server_key, &secrets.agent_key)
+}
+
+// The agent's side of the handshake.
+pub(crate) fn accept(stream: TcpStream, key: &str) -> Result<(Sender, Receiver), Error> {
+    let key = parse_key(key)?;
+    let mut stream = stream;
+    let deadline = time::Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut buffer = Vec::new();
+    let theirs = match recv_handshake(&mut stream, &mut buffer, deadline)? {
+        Handshake::Hello { version, .. } if version != VERSION => {
+            // Let the server report the mismatch too.
+            send_handshake(
+                &mut stream,
+                &Handshake::Hello {
+                    version: VERSION,
+                    nonce: Vec::new(),
+                    proof: Vec::new(),
+                },
+            )?;
+            return Err(Error::Command(format!(
+                "the server speaks protocol version {}, we speak {}",
+                version, VERSION
+            )));
+        }
+        Handshake::Hello { nonce, .. } => nonce,
+        Handshake::Proof { .. } => return Err(unexpected()),
+    };
+
+    let ours = random_nonce()?;
+    let secrets = Secrets::derive(&key, &theirs, &ours)?;
+    send_handshake(
+        &mut stream,
+        &Handshake::Hello {
+            version: VERSION,
+            nonce: ours.to_vec(),
+            proof: secrets.prove(&secrets.agent_proof)?,
+        },
+    )?;
+    match recv_handshake(&mut stream, &mut buffer, deadline)? {
+        Handshake::Proof { proof } => secrets.verify(&secrets.server_proof, &proof)?,
+        Handshake::Hello { .. } => return Err(unexpected()),
+    }
+    split(stream, buffer, &secrets.agent_key, &secrets.server_key)
+}
+
+// The agent key as bytes. Anything shorter than MIN_KEY_LEN could be guessed
+// offline from a recorded handshake.
+pub(crate) fn parse_key(key: &str) -> Result<Vec<u8>, Error> {
+    let key = key.trim();
+    let bytes: Option<Vec<u8>> = match key.len() % 2 {
+        0 => (0..key.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(key.get(i..i + 2)?, 16).ok())
+            .collect(),
+        _ => None,
+    };
+    match bytes {
+        Some(bytes) if bytes.len() >= MIN_KEY_LEN => Ok(bytes),
+        _ => Err(Error::Config(format!(
+            "the agent key must be at least {} random bytes as hex, \
+             e.g. from `openssl rand -hex {}`",
+            MIN_KEY_LEN, MIN_KEY_LEN
+        ))),
+    }
+}
+
+struct Secrets {
+    // Both nonces, server first; what the proofs are over.
+    transcript: Vec<u8>,
+    agent_proof: Vec<u8>,
+    server_proof: Vec<u8>,
+    server_key: Vec<u8>,
+    agent_key: Vec<u8>,
+}
+
+impl Secrets {
+    // HKDF with the transcript as salt, so every connection gets its own keys.
+    fn derive(key: &[u8], server: &[u8], agent: &[u8]) -> Result<Secrets, Error> {
+        if server.len() != NONCE_LEN || agent.len() != NONCE_LEN {
+            return Err(Error::Auth("invalid handshake nonce".to_string()));
+        }
+        let transcript = [server, agent].concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(&transcript), key);
+        let expand = |label: &str| {
+            let mut okm = vec![0; 32];
+            hkdf.expand(format!("upsync {}", label).as_bytes(), &mut okm)
+                .map(|()| okm)
+                .map_err(|_| Error::Auth("could not derive the session keys".to_string()))
+        };
+        Ok(Secrets {
+            agent_proof: expand("agent proof")?,
+            server_proof: expand("server proof")?,
+            server_key: expand("server key")?,
+            agent_key: expand("agent key")?,
+            transcript,
+        })
+    }
+
+    fn prove(&self, proof_key: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(self.hmac(proof_key)?.finalize().into_bytes().to_vec())
+    }
+
+    fn verify(&self, proof_key: &[u8], proof: &[u8]) -> Result<(), Error> {
+        self.hmac(proof_key)?
+            .verify_slice(proof)
+            .map_err(|_| Error::Auth("the other side does not know the agent key".to_string()))
+    }
+
+    fn hmac(&self, proof_key: &[u8]) -> Result<Hmac<Sha256>, Error> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(proof_key)
+            .map_err(|_| Error::Auth("invalid proof key".to_string()))?;
+        mac.update(&self.transcript);
+        Ok(mac)
+    }
+}
+
+const NONCE_LEN: usize = 32;
+
+fn random_nonce() -> Result<[u8; NONCE_LEN], Error> {
+    let mut nonce = [0; NONCE_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+    Ok(nonce)
+}
+
+fn unexpected() -> Error {
+    Error::Auth("unexpected handshake message".to_string())
+}
+
+fn split(
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    send_key: &[u8],
+    recv_key: &[u8],
+) -> Result<(Sender, Receiver), Error> {
+    let sender = Sender {
+        stream: stream.try_clone()?,
+        cipher: ChaCha20Poly1305::new(Key::from_slice(send_key)),
+        sent: 0,
+    };
+    let receiver = Receiver {
+        stream,
+        cipher: ChaCha20Poly1305::new(Key::from_slice(recv_key)),
+        received: 0,
+        buffer,
+    };
+    Ok((sender, receiver))
+}
+
+// 12 bytes: four zeros and the frame counter.
+fn nonce(id: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&id.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+fn send_handshake(stream: &mut TcpStream, message: &Handshake) -> Result<(), Error> {
+    let data = serde_json::to_vec(message).map_err(io::Error::from)?;
+    write_frame(stream, &data)
+}
+
+fn recv_handshake(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    deadline: time::Instant,
+) -> Result<Handshake, Error> {
+    let data = read_frame(&mut Deadline { stream, deadline }, buffer)?;
+    serde_json::from_slice(&data).map_err(|_| unexpected())
+}
+
+// Gives every read only the time left until the deadline, so a peer can't
+// stretch a frame by sending it a byte at a time.
+struct Deadline<'a> {
+    stream: &'a mut TcpStream,
+    deadline: time::Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self
+            .deadline
+            .saturating_duration_since(time::Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "the handshake took too long",
+            ));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+fn write_frame(stream: &mut TcpStream, data: &[u8]) -> Result<(), Error> {
+    let mut frame = (data.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(data);
+    stream.write_all(&frame)?;
+    Ok(())
+}
+
+// Reads until the buffer holds a whole frame and takes it out.
+fn read_frame(stream: &mut impl Read, buffer: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+    loop {
+        if buffer.len() >= 4 {
+            let len = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+            if len > MAX_FRAME {
+                return Err(Error::Command(format!(
+                    "frame of {} bytes is too large",
+                    len
+                )));
+            }
+            if buffer.len() >= 4 + len {
+                let frame = buffer[4..4 + len].to_vec();
+                buffer.drain(..4 + len);
+                return Ok(frame);
+            }
+        }
+
+        let mut chunk = [0; 4096];
+        match stream.read(&mut chunk)? {
+            0 => return Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+            n => buffer.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    type Endpoint = Result<(Sender, Receiver), Error>;
+
+    const KEY: &str = "8d2ef6c6a0b1d4e37f5c9a1b2c3d4e5f60718293a4b5c6d7e8f9a0b1c2d3e4f5";
+    const OTHER_KEY: &str = "0d2ef6c6a0b1d4e37f5c9a1b2c3d4e5f60718293a4b5c6d7e8f9a0b1c2d3e4f5";
+
+    // An agent and a server in one process, connected over loopback.
+    fn endpoints(server_key: &'static str, agent_key: &'static str) -> (Endpoint, Endpoint) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let agent = thread::spawn(move || accept(listener.accept().unwrap().0, agent_key));
+        let server = connect(TcpStream::connect(address).unwrap(), server_key);
+        (server, agent.join().unwrap())
+    }
+
+    #[test]
+    fn exchanges_messages() {
+        let (server, agent) = endpoints(KEY, KEY);
+        let (mut server_tx, mut server_rx) = server.unwrap();
+        let (mut agent_tx, mut agent_rx) = agent.unwrap();
+
+        let countdown = Message::Countdown {
+            default: "suspend".to_string(),
+            seconds: 30,
+            actions: vec!["suspend".to_string(), "poweroff".to_string()],
+            blockers: String::new(),
+        };
+        assert_eq!(server_tx.send(&Message::PowerLost).unwrap(), 0);
+        assert_eq!(server_tx.send(&countdown).unwrap(), 1);
+        assert_eq!(agent_rx.recv().unwrap().message, Message::PowerLost);
+        assert_eq!(
+            agent_rx.recv().unwrap(),
+            Envelope {
+                id: 1,
+                message: countdown
+            }
+        );
+
+        agent_tx.send(&Message::Ack { id: 1 }).unwrap();
+        assert_eq!(server_rx.recv().unwrap().message, Message::Ack { id: 1 });
+
+        server_rx
+            .set_timeout(Some(time::Duration::from_millis(50)))
+            .unwrap();
+        assert!(timed_out(&server_rx.recv().unwrap_err()));
+
+        drop(agent_tx);
+        drop(agent_rx);
+        assert!(closed(&server_rx.recv().unwrap_err()));
+    }
+
+    #[test]
+    fn rejects_a_wrong_key() {
+        let (server, agent) = endpoints(KEY, OTHER_KEY);
+        assert!(matches!(server, Err(Error::Auth(_))));
+        assert!(agent.is_err());
+    }
+
+    #[test]
+    fn requires_a_long_random_key() {
+        assert_eq!(parse_key(&format!(" {}\n", KEY)).unwrap().len(), 32);
+        assert!(parse_key("hunter2").is_err());
+        assert!(parse_key(&KEY[..62]).is_err());
+        assert!(parse_key(&KEY.replace('8', "x")).is_err());
+
+        // A peer that never says hello is dropped.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _silent = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert!(timed_out(&accept(stream, KEY).err().unwrap()));
+    }
+
+    #[test]
+    fn drops_a_trickling_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        // A frame header, then a byte every so often, each well within the
+        // handshake timeout.
+        thread::spawn(move || {
+            peer.write_all(&1000u32.to_be_bytes())?;
+            for _ in 0..40 {
+                thread::sleep(time::Duration::from_millis(250));
+                peer.write_all(b"{")?;
+            }
+            io::Result::Ok(())
+        });
+
+        let start = time::Instant::now();
+        let err = accept(stream, KEY).err().unwrap();
+        assert!(timed_out(&err));
+        assert!(start.elapsed() < HANDSHAKE_TIMEOUT + time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn rejects_replayed_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let agent = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (_, mut receiver) = accept(stream, KEY).unwrap();
+            let first = receiver.recv().unwrap().message;
+            (first, receiver.recv())
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut raw = stream.try_clone().unwrap();
+        let (sender, _receiver) = connect(stream, KEY).unwrap();
+        // The first frame sent again in place of the second.
+        let sealed = sender
+            .cipher
+            .encrypt(
+                &nonce(0),
+                serde_json::to_vec(&Envelope {
+                    id: 0,
+                    message: Message::Command {
+                        action: "poweroff".to_string(),
+                    },
+                })
+                .unwrap()
+                .as_slice(),
+            )
+            .unwrap();
+        write_frame(&mut raw, &sealed).unwrap();
+        write_frame(&mut raw, &sealed).unwrap();
+        drop(raw);
+
+        let (first, replayed) = agent.join().unwrap();
+        assert_eq!(
+            first,
+            Message::Command {
+                action: "poweroff".to_string()
+            }
+        );
+        assert!(matches!(replayed, Err(Error::Auth(_))));
+    }
+
+    #[test]
+    fn reads_frames_in_pieces() {
+        let mut buffer = Vec::new();
+        let mut input: &[u8] = &[0, 0, 0, 2, b'h', b'i', 0, 0];
+        assert_eq!(read_frame(&mut input, &mut buffer).unwrap(), b"hi");
+        assert!(read_frame(&mut input, &mut buffer).is_err());
+        assert_eq!(buffer, [0, 0]);
+
+        let mut too_large: &[u8] = &[0, 1, 0, 1];
+        assert!(read_frame(&mut too_large, &mut Vec::new()).is_err());
+    }
+}
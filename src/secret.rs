@@ -0,0 +1,311 @@
+use crate::{config, core, Error};
+use log::warn;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::{env, str::FromStr};
+
+// ClientConfig::key either holds the password itself or says where to find
+// it:
+//
+//   file:/path        a file only readable by its owner
+//   env:VAR           an environment variable
+//   credential:name   a systemd credential ($CREDENTIALS_DIRECTORY/name)
+//   keyring:name      the Secret Service keyring (via secret-tool)
+//   age:/path.age     an age encrypted file, decrypted with identity.txt
+//                     from the user config directory
+//   plain:password    an inline password that happens to contain a ':'
+//
+// Anything else is an inline password, as written by older versions.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Secret<'a> {
+    Plain(&'a str),
+    File(&'a Path),
+    Env(&'a str),
+    Credential(&'a str),
+    Keyring(&'a str),
+    Age(&'a Path),
+}
+
+impl<'a> Secret<'a> {
+    pub fn parse(reference: &'a str) -> Result<Secret<'a>, Error> {
+        let Some((scheme, target)) = reference.split_once(':') else {
+            return Ok(Secret::Plain(reference));
+        };
+        let named = || match target.is_empty() {
+            true => Err(Error::Secret(format!(
+                "'{}' has nothing after the ':'",
+                reference
+            ))),
+            false => Ok(target),
+        };
+
+        match scheme {
+            "plain" => Ok(Secret::Plain(target)),
+            "file" => Ok(Secret::File(Path::new(named()?))),
+            "env" => Ok(Secret::Env(named()?)),
+            "credential" => Ok(Secret::Credential(named()?)),
+            "keyring" => Ok(Secret::Keyring(named()?)),
+            "age" => Ok(Secret::Age(Path::new(named()?))),
+            // Passwords written before references existed may contain a ':'.
+            _ => Ok(Secret::Plain(reference)),
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        matches!(self, Secret::Plain(_))
+    }
+}
+
+pub fn resolve(reference: &str) -> Result<String, Error> {
+    match Secret::parse(reference)? {
+        Secret::Plain(password) => Ok(password.to_string()),
+        Secret::File(path) => read_private(path),
+        Secret::Env(var) => env::var(var)
+            .map_err(|_| Error::Secret(format!("environment variable {} is not set", var))),
+        Secret::Credential(name) => {
+            let dir = env::var("CREDENTIALS_DIRECTORY").map_err(|_| {
+                Error::Secret(format!(
+                    "CREDENTIALS_DIRECTORY is not set; add LoadCredentialEncrypted={} to the {} service",
+                    name,
+                    core::APPNAME
+                ))
+            })?;
+            read_private(&Path::new(&dir).join(name))
+        }
+        Secret::Keyring(name) => command(
+            "secret-tool",
+            &["lookup", "service", core::APPNAME, "client", name],
+            None,
+        ),
+        Secret::Age(path) => command(
+            "age",
+            &[
+                "--decrypt",
+                "-i",
+                &age_identity().to_string_lossy(),
+                &path.to_string_lossy(),
+            ],
+            None,
+        ),
+    }
+}
+
+fn read_private(path: &Path) -> Result<String, Error> {
+    let metadata =
+        fs::metadata(path).map_err(|err| Error::Secret(format!("{}: {}", path.display(), err)))?;
+    if metadata.permissions().mode() & 0o077 != 0 {
+        warn!(
+            "{} is readable by other users, run: chmod 600 {}",
+            path.display(),
+            path.display()
+        );
+    }
+
+    let secret = fs::read_to_string(path)?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Runs a helper program and returns its stdout without the trailing newline.
+fn command(program: &str, args: &[&str], input: Option<&str>) -> Result<String, Error> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| Error::Secret(format!("could not run {}: {}", program, err)))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.unwrap_or_default().as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::Secret(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Store {
+    Plain,
+    File,
+    Credential,
+    Keyring,
+    Age,
+}
+
+impl FromStr for Store {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "plain" => Ok(Store::Plain),
+            "file" => Ok(Store::File),
+            "credential" => Ok(Store::Credential),
+            "keyring" => Ok(Store::Keyring),
+            "age" => Ok(Store::Age),
+            _ => Err(Error::Usage(format!(
+                "Unknown secret store '{}', use plain, file, credential, keyring or age",
+                input
+            ))),
+        }
+    }
+}
+
+// The server usually runs as a system service without a desktop session, so
+// the keyring is never picked on its own. An encrypted systemd credential
+// needs root; everyone else gets a private secrets file.
+pub fn safest_store() -> Store {
+    let root = matches!(command("id", &["-u"], None).as_deref(), Ok("0"));
+    if root && command("systemd-creds", &["--version"], None).is_ok() {
+        Store::Credential
+    } else {
+        Store::File
+    }
+}
+
+// Stores the password for a client and returns the reference to put in the
+// config instead of it.
+pub fn store(store: Store, client: &str, password: &str) -> Result<String, Error> {
+    let name: String = client
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+
+    match store {
+        Store::Plain => Ok(match password.contains(':') {
+            true => format!("plain:{}", password),
+            false => password.to_string(),
+        }),
+        Store::File => {
+            let path = secrets_dir()?.join(&name);
+            config::write_atomic(&path, password)?;
+            Ok(format!("file:{}", path.display()))
+        }
+        Store::Credential => {
+            let credential = format!("{}-{}", core::APPNAME, name);
+            let path = Path::new("/etc/credstore.encrypted").join(&credential);
+            fs::create_dir_all(path.parent().unwrap_or(Path::new("/")))?;
+            command(
+                "systemd-creds",
+                &[
+                    "encrypt",
+                    &format!("--name={}", credential),
+                    "-",
+                    &path.to_string_lossy(),
+                ],
+                Some(password),
+            )?;
+            println!(
+                "Add 'LoadCredentialEncrypted={}' to the [Service] section of {}.service",
+                credential,
+                core::APPNAME
+            );
+            Ok(format!("credential:{}", credential))
+        }
+        Store::Keyring => {
+            command(
+                "secret-tool",
+                &[
+                    "store",
+                    &format!("--label={} {}", core::APPNAME, client),
+                    "service",
+                    core::APPNAME,
+                    "client",
+                    client,
+                ],
+                Some(password),
+            )?;
+            Ok(format!("keyring:{}", client))
+        }
+        Store::Age => {
+            let identity = age_identity();
+            if !identity.exists() {
+                command("age-keygen", &["-o", &identity.to_string_lossy()], None)?;
+                fs::set_permissions(&identity, fs::Permissions::from_mode(0o600))?;
+            }
+            let recipient = command("age-keygen", &["-y", &identity.to_string_lossy()], None)?;
+            let path = secrets_dir()?.join(format!("{}.age", name));
+            command(
+                "age",
+                &["-e", "-r", &recipient, "-o", &path.to_string_lossy()],
+                Some(password),
+            )?;
+            Ok(format!("age:{}", path.display()))
+        }
+    }
+}
+
+fn secrets_dir() -> Result<PathBuf, Error> {
+    let dir = config::user_config_dir().join("secrets");
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    Ok(dir)
+}
+
+fn age_identity() -> PathBuf {
+    config::user_config_dir().join("identity.txt")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_references() {
+        assert_eq!(Secret::parse("hunter2").unwrap(), Secret::Plain("hunter2"));
+        assert_eq!(Secret::parse("plain:a:b").unwrap(), Secret::Plain("a:b"));
+        assert_eq!(
+            Secret::parse("pass:word").unwrap(),
+            Secret::Plain("pass:word")
+        );
+        assert_eq!(
+            Secret::parse("env:DESKTOP_PW").unwrap(),
+            Secret::Env("DESKTOP_PW")
+        );
+        assert_eq!(
+            Secret::parse("file:/etc/upsync/desktop").unwrap(),
+            Secret::File(Path::new("/etc/upsync/desktop"))
+        );
+        assert!(Secret::parse("keyring:").is_err());
+        assert!(Secret::parse("file:").is_err());
+
+        // An older inline password may end in a ':' too.
+        assert_eq!(
+            Secret::parse("hunter2:").unwrap(),
+            Secret::Plain("hunter2:")
+        );
+        assert_eq!(Secret::parse("plain:").unwrap(), Secret::Plain(""));
+    }
+
+    #[test]
+    fn resolves_env_and_file() {
+        env::set_var("UPSYNC_TEST_SECRET", "from env");
+        assert_eq!(resolve("env:UPSYNC_TEST_SECRET").unwrap(), "from env");
+
+        let path = env::temp_dir().join(format!("upsync-secret-{}", std::process::id()));
+        config::write_atomic(&path, "from file\n").unwrap();
+        assert_eq!(
+            resolve(&format!("file:{}", path.display())).unwrap(),
+            "from file"
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
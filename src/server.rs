@@ -1,236 +1,446 @@
-use crate::core;
-use log::{debug, error, info, trace, warn};
-use std::error::Error;
-use std::path::PathBuf;
-use std::sync::OnceLock;
-use std::{env, process, thread, time};
-
-static CONFIG: OnceLock<core::ClientConfig> = OnceLock::new();
-
-fn get_config() -> &'static core::ClientConfig {
-    let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-    let config_path: PathBuf = [home.as_str(), ".local/share/upsync/config.json"]
-        .iter()
-        .collect();
-
-    // read data from json once to avoide any unxpected errors,
-    CONFIG.get_or_init(|| match core::read_json(config_path.as_path()) {
-        Ok(data) => data,
-        Err(err) => {
-            error!(
-                "{} \nPlease run the setup command: {} setup.",
-                err,
-                core::APPNAME
-            );
-            process::exit(1);
-        }
+use crate::config::{self, Config, Layered};
+use crate::monitor::{Event, LaptopBattery, Monitor, MonitorHandle, Policy, PowerState, Scripted};
+use crate::state::{ClientState, OutageState, Phase};
+use crate::{core, state, Error};
+use log::{error, info, warn};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, process, thread, time};
+use tokio::signal::unix::{signal, SignalKind};
+
+use core::ClientConfig;
+
+fn get_config(path: Option<&Path>) -> Result<Layered, Error> {
+    config::load_layered(path).map_err(|err| {
+        Error::Config(format!(
+            "{} \nPlease run the setup command: {} setup.",
+            err,
+            core::APPNAME
+        ))
     })
 }
 
-pub fn run_server() {
-    if let false = status() {
-        offline();
+// Lets `upsync config` find the running server to ask it to reload. Root's
+// is in /run and anyone else's in their own runtime directory, never where
+// another user could plant one.
+pub(crate) fn pid_path() -> Option<PathBuf> {
+    let name = format!("{}.pid", core::APPNAME);
+    match uid() {
+        Some(0) => Some(Path::new("/run").join(name)),
+        _ => env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join(name)),
     }
+}
 
-    let mut log = true;
+fn uid() -> Option<u32> {
+    fs::metadata("/proc/self").ok().map(|meta| meta.uid())
+}
 
-    loop {
-        trace!("Main loop!");
-        thread::sleep(time::Duration::from_secs(get_config().delay_between_tasks));
-        let battery = match core::battery_present() {
-            Ok(state) => state,
-            Err(err) => {
-                error!("Error: {}", err);
-                continue;
-            }
-        };
+// Sends SIGHUP to the running server. Returns false if none is running.
+pub(crate) fn signal_reload() -> Result<bool, Error> {
+    let Some(path) = pid_path() else {
+        return Ok(false);
+    };
+    let pid: u32 = match fs::read_to_string(&path).map(|pid| pid.trim().parse()) {
+        Ok(Ok(pid)) => pid,
+        _ => return Ok(false),
+    };
+    // A stale file may name any process by now, and SIGHUP ends most.
+    let ours = fs::metadata(&path).is_ok_and(|meta| Some(meta.uid()) == uid());
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+    if !ours || comm.trim() != core::APPNAME {
+        warn!(
+            "Ignoring {}: process {} is not a running {} of ours",
+            path.display(),
+            pid,
+            core::APPNAME
+        );
+        return Ok(false);
+    }
+    core::run_command(&format!("kill -HUP {}", pid))
+}
 
-        match battery {
-            battery::State::Discharging => {
-                warn!("device is discharging.");
-                log = true;
-                thread::sleep(time::Duration::from_secs(get_config().delay_between_tasks));
-                state_discharging();
-                continue;
-            }
-            other => {
-                debug!("Device is charging: {:?}", other);
-                if log {
-                    info!("Device is charging");
-                    log = false
-                }
-                continue;
+// Removes the PID file again when the server stops.
+struct PidFile(PathBuf);
+
+impl PidFile {
+    fn create() -> Option<PidFile> {
+        let path = pid_path()?;
+        match fs::write(&path, process::id().to_string()) {
+            Ok(()) => Some(PidFile(path)),
+            Err(err) => {
+                warn!("Could not write {}: {}", path.display(), err);
+                None
             }
         }
     }
 }
 
-fn status() -> bool {
-    match core::device_status(&get_config().ip) {
-        Ok(status) => status,
-        Err(err) => {
-            error!("{}", err);
-            info!("Assuming device is offline");
-            false
-        }
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
     }
 }
 
-fn state_discharging() {
-    if status() {
-        info!("client is online");
+pub fn run_server(path: Option<&Path>) -> Result<(), Error> {
+    let layered = get_config(path)?;
+    let _pid_file = PidFile::create();
 
-        thread::sleep(time::Duration::from_secs(get_config().delay_between_tasks));
+    let notify_command = Arc::new(Mutex::new(layered.config.notify_command.clone()));
+    let mut monitor = Monitor::builder()
+        .power_source(LaptopBattery)
+        .policy(policy(&layered.config))
+        .clients(layered.config.clients)
+        .state_file(state::default_path())
+        .build()?;
+    notify(monitor.subscribe(), notify_command.clone());
+    watch_config(
+        path.map(Path::to_path_buf),
+        layered.files,
+        monitor.handle(),
+        notify_command,
+    )?;
+    stop_on_signal(monitor.handle())?;
+    monitor.run()
+}
 
-        let battery = match core::battery_present() {
-            Ok(state) => state,
-            Err(err) => {
-                error!("Unable to read battery status: {}", err);
-                info!("Returning charging as default");
-                battery::State::Charging
-            }
-        };
+// Passes the events that need someone's attention to notify_command.
+fn notify(events: Receiver<Event>, command: Arc<Mutex<String>>) {
+    thread::spawn(move || {
+        for event in events {
+            let (kind, client, message) = match event {
+                Event::ActionFailed { client, error } => ("action-failed", client, error),
+                Event::WakeFailed { client, error } => ("wake-failed", client, error),
+                Event::WakeGaveUp { client, attempts } => {
+                    let message = format!(
+                        "{} did not come back after {} WOL attempt(s)",
+                        client, attempts
+                    );
+                    ("wake-gave-up", client, message)
+                }
+                Event::PostWakeFailed { client, error } => ("post-wake-failed", client, error),
+                _ => continue,
+            };
 
-        match battery {
-            battery::State::Discharging => {
-                debug!("Opening popup in client");
-                send_device_to();
+            let command = command
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .clone();
+            if command.is_empty() {
+                continue;
             }
-            _ => {
-                info!("power is back.");
+            let status = process::Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .arg(core::APPNAME)
+                .args([kind, &client, &message])
+                .status();
+            match status {
+                Ok(status) if status.success() => {
+                    info!("Sent {} notification for {}", kind, client)
+                }
+                Ok(status) => error!("notify_command exited with {}", status),
+                Err(err) => error!("Could not run notify_command: {}", err),
             }
         }
-    } else {
-        offline();
+    });
+}
+
+fn policy(config: &Config) -> Policy {
+    let interval = time::Duration::from_secs(config.delay_between_tasks);
+    Policy {
+        poll_interval: interval,
+        grace_period: interval,
+        wake: config.wake_policy,
+        wake_stagger: time::Duration::from_secs(config.wake_stagger),
+        shutdown_timeout: time::Duration::from_secs(config.shutdown_timeout),
+        defer_limit: time::Duration::from_secs(config.defer_limit),
+        ..Policy::default()
     }
 }
 
-fn send_device_to() {
-    match get_config().popup {
-        true => {
-            let command: String = format!(
-                "export DISPLAY=:0 && export WAYLAND_DISPLAY=wayland-0 && MOD=gui {}",
-                core::GUI_APPNAME
-            );
-            match run_ssh(command) {
-                Ok(()) => info!("popup open surcess"),
-                Err(err) => {
-                    error!("popup open error: {}", err);
-                }
+// Reloads the config on SIGHUP or when one of its files changes. A config
+// that fails to load or validate is reported and the running one kept.
+fn watch_config(
+    path: Option<PathBuf>,
+    files: Vec<PathBuf>,
+    handle: MonitorHandle,
+    notify_command: Arc<Mutex<String>>,
+) -> Result<(), Error> {
+    let hangup = Arc::new(AtomicBool::new(false));
+    listen_for_hangup(hangup.clone())?;
+
+    thread::spawn(move || {
+        let mut watched = watched_paths(&files);
+        let mut stamps = modified(&watched);
+        loop {
+            thread::sleep(time::Duration::from_secs(2));
+            let signalled = hangup.swap(false, Ordering::SeqCst);
+            let changed = modified(&watched) != stamps;
+            if !signalled && !changed {
+                continue;
             }
-        }
 
-        false => {
-            let action = core::get_default_server(&get_config().default_behaviour);
-            let command = format!("systemctl {}", action,);
-            match run_ssh(command) {
-                Ok(()) => info!("Device send to {}", action),
-                Err(err) => {
-                    error!("popup open error: {}", err);
+            info!(
+                "Reloading config ({})",
+                if signalled { "SIGHUP" } else { "file changed" }
+            );
+            match config::load_layered(path.as_deref()) {
+                Ok(layered) => {
+                    watched = watched_paths(&layered.files);
+                    *notify_command.lock().unwrap_or_else(|err| err.into_inner()) =
+                        layered.config.notify_command.clone();
+                    let policy = policy(&layered.config);
+                    if let Err(err) = handle.reconfigure(layered.config.clients, policy) {
+                        error!("Keeping the current config: {}", err);
+                    }
                 }
+                Err(err) => error!("Keeping the current config: {}", err),
             }
+            stamps = modified(&watched);
         }
-    }
-
-    wait_for_power()
+    });
+    Ok(())
 }
 
-use ssh2::Session;
-use std::net::TcpStream as TcpStreamSTD;
-
-fn run_ssh(command: String) -> Result<(), Box<dyn Error>> {
-    let tcp = TcpStreamSTD::connect("192.168.1.240:22")?;
-    let mut sess = Session::new()?;
-    sess.set_tcp_stream(tcp);
-    sess.handshake().unwrap();
-    sess.userauth_password(&get_config().user, &get_config().key)?;
-
-    let mut channel = sess.channel_session()?;
-
-    channel.exec(&command)?;
+// SIGHUP would otherwise terminate the server, so the handler is installed
+// before returning.
+fn listen_for_hangup(flag: Arc<AtomicBool>) -> Result<(), Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let mut hangup = {
+        let _guard = runtime.enter();
+        signal(SignalKind::hangup())?
+    };
 
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            while hangup.recv().await.is_some() {
+                flag.store(true, Ordering::SeqCst);
+            }
+        })
+    });
     Ok(())
 }
 
-fn wait_for_power() {
-    info!("Device is discharging. Waiting for power to return.");
-    loop {
-        trace!("wait_for_power loop");
-        thread::sleep(time::Duration::from_secs(get_config().delay_between_tasks));
+// SIGTERM and SIGINT stop the monitor, so the server exits through
+// run_server and removes its PID file.
+fn stop_on_signal(handle: MonitorHandle) -> Result<(), Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let (mut terminate, mut interrupt) = {
+        let _guard = runtime.enter();
+        (
+            signal(SignalKind::terminate())?,
+            signal(SignalKind::interrupt())?,
+        )
+    };
 
-        let battery = match core::battery_present() {
-            Ok(state) => state,
-            Err(err) => {
-                error!("Unable to read battery status: {}", err);
-                info!("Returning discharging as default");
-                battery::State::Discharging
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = interrupt.recv() => {}
             }
-        };
+            info!("Stopping");
+            handle.stop();
+        })
+    });
+    Ok(())
+}
 
-        match battery {
-            battery::State::Discharging => {
-                debug!("Device is discharging");
-                continue;
-            }
-            _ => {
-                info!("Device is charging and power is back");
-                if get_config().wake {
-                    wake_the_pc();
-                }
-                break;
-            }
+// The loaded files and the directories that may gain one, so creating
+// /etc/upsync/config.toml is noticed too.
+fn watched_paths(files: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths = vec![
+        config::legacy_config_dir(),
+        PathBuf::from(config::SYSTEM_CONFIG_DIR),
+        config::user_config_dir(),
+    ];
+    for file in files {
+        paths.push(file.clone());
+        if let Some(dir) = file.parent() {
+            paths.push(dir.to_path_buf());
         }
     }
+    paths.sort();
+    paths.dedup();
+    paths
 }
 
-fn offline() {
-    info!("client is online");
-    loop {
-        trace!("Offline state loop");
-        thread::sleep(time::Duration::from_secs(get_config().delay_between_tasks));
-        match status() {
-            true => break,
+fn modified(paths: &[PathBuf]) -> Vec<Option<time::SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
 
-            false => {
-                info!("client is offline")
+enum Step {
+    Pass,
+    Fail,
+    Skip,
+}
+
+// Runs one outage drill through the same Monitor as the server, with the
+// battery replaced by a script: the power is out for `duration`, then back.
+// Ordering, deferral, wake policy and wake verification all apply, and
+// everything sent to the clients is real. The drill keeps its own state file
+// next to the server's. Fails with Error::Check if any step failed.
+pub fn simulate_outage(
+    path: Option<&Path>,
+    duration: time::Duration,
+    client: Option<&str>,
+) -> Result<(), Error> {
+    let config = get_config(path)?.config;
+    let policy = policy(&config);
+    if duration <= policy.grace_period {
+        return Err(Error::Usage(format!(
+            "--duration must be longer than the {}s grace period (delay_between_tasks)",
+            policy.grace_period.as_secs()
+        )));
+    }
+    let clients = match client {
+        Some(name) => vec![config
+            .client(name)
+            .cloned()
+            .ok_or_else(|| Error::Usage(format!("Unknown client: {}", name)))?],
+        None => config.clients,
+    };
+
+    let state_file = state::default_path().with_file_name("simulation.json");
+    let _ = fs::remove_file(&state_file);
+    let interval = policy.poll_interval;
+    let mut monitor = Monitor::builder()
+        .power_source(Scripted::new([
+            (PowerState::Battery, duration),
+            (PowerState::Mains, time::Duration::ZERO),
+        ]))
+        .policy(policy)
+        .clients(clients)
+        .state_file(&state_file)
+        .build()?;
+    let events = monitor.subscribe();
+    let handle = monitor.handle();
+
+    info!("Simulating power loss for {} seconds", duration.as_secs());
+    let running = thread::spawn(move || monitor.run().map(|()| monitor));
+
+    // Over once power is back and the restore has finished with every client
+    // woken or given up on, which puts the state file back to mains.
+    let mut seen = Vec::new();
+    let mut restored = false;
+    loop {
+        match events.recv_timeout(interval) {
+            Ok(event) => {
+                info!("{:?}", event);
+                restored |= event == Event::PowerRestored;
+                seen.push(event);
             }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if restored && OutageState::load(&state_file).phase == Phase::Mains {
+            break;
         }
     }
+    handle.stop();
+    let monitor = running
+        .join()
+        .map_err(|_| Error::Check("the monitor stopped unexpectedly".to_string()))??;
+    seen.extend(events.try_iter());
+    let _ = fs::remove_file(&state_file);
+
+    let mut failed = 0;
+    for client in monitor.clients() {
+        failed += report(client, monitor.state().clients.get(&client.name), &seen);
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(Error::Check(format!("{} simulation step(s) failed", n))),
+    }
 }
 
-fn wake_the_pc() {
-    let command = format!("wakeonlan {}", get_config().mac_address);
+// Prints the report for one client and returns the number of failed steps.
+fn report(config: &ClientConfig, state: Option<&ClientState>, events: &[Event]) -> usize {
+    let name = || config.name.clone();
+    let lost = events
+        .iter()
+        .position(|event| *event == Event::PowerLost)
+        .unwrap_or(events.len());
+    let woken = events
+        .iter()
+        .position(|event| *event == Event::WakeSent { client: name() });
+    let happened = |from: usize, event: Event| events[from..].contains(&event);
 
-    if !status() {
-        info!(
-            "Client is offline sending wol command in {} seconds",
-            get_config().default_delay as u64
-        );
-        thread::sleep(time::Duration::from_secs(get_config().default_delay as u64));
-
-        let wol = core::run_command(&command);
-
-        match wol {
-            Ok(result) => {
-                if result {
-                    info!("WOL command succeeded!");
-                } else {
-                    error!("WOL command failed!");
-                    info!(
-                    "Verify the mac address of the client and run '{} setup' to reconfiger to settings",
-                    core::APPNAME
-                );
-                }
-            }
-            Err(err) => {
-                error!("error sending wol {}", err);
-                info!(
-                "Verify the mac address of the client and run '{} setup' to reconfiger to settings",
-                core::APPNAME
+    let mut report: Vec<(&str, Step)> = Vec::new();
+    let online = state.and_then(|state| state.online_before);
+    report.push((
+        "client reachable before outage",
+        online.map_or(Step::Skip, step),
+    ));
+
+    let sent = state.and_then(|state| match (&state.action, &state.error) {
+        (Some(_), _) => Some(true),
+        (None, Some(_)) => Some(false),
+        (None, None) => None,
+    });
+    report.push(("popup/action sent to client", sent.map_or(Step::Skip, step)));
+
+    let expect_offline =
+        config.popup || !matches!(config.default_behaviour, core::Behaviour::Ignore);
+    let left = match sent {
+        Some(true) if expect_offline => {
+            step(happened(lost, Event::ClientOffline { client: name() }))
+        }
+        _ => Step::Skip,
+    };
+    report.push(("client left the network", left));
+
+    let (wake, back) = match woken {
+        Some(i) => (
+            Step::Pass,
+            step(happened(i, Event::ClientOnline { client: name() })),
+        ),
+        None => {
+            let failed = events.iter().any(
+                |event| matches!(event, Event::WakeFailed { client, .. } if *client == config.name),
             );
-            }
+            (if failed { Step::Fail } else { Step::Skip }, Step::Skip)
         }
+    };
+    report.push(("wake-on-lan sent", wake));
+    report.push(("client back online", back));
+
+    println!("Outage simulation report for {}:", config.name);
+    let mut failed = 0;
+    for (name, result) in &report {
+        let label = match result {
+            Step::Pass => "PASS",
+            Step::Fail => {
+                failed += 1;
+                "FAIL"
+            }
+            Step::Skip => "SKIP",
+        };
+        println!("  [{}] {}", label, name);
+    }
+    let deferred = events.iter().any(
+        |event| matches!(event, Event::ActionDeferred { client, .. } if *client == config.name),
+    );
+    if deferred {
+        println!("  (the action was held back by inhibitor locks or critical processes)");
+    }
+    failed
+}
+
+fn step(ok: bool) -> Step {
+    if ok {
+        Step::Pass
     } else {
-        info!("Client is online, skipping WOL.");
+        Step::Fail
     }
 }
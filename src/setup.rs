@@ -1,33 +1,596 @@
-use crate::core;
-use serde_json::to_writer;
-use std::env;
-use std::path::PathBuf;
-use std::{fs::create_dir_all, fs::File, process};
-
-pub fn server_setup() {
-    println!("Are you sure you want to delete the existing config and start creating a new config? (y/n)");
-    let input = core::user_input().unwrap().to_lowercase();
-    match input.as_str() {
-        "y" => gen_json(),
-        "n" => process::exit(0),
-        _ => {
-            println!("Please enter a valid option.");
-            process::exit(1);
-        }
-    }
-}
-
-fn gen_json() {
-    use core::ClientConfig;
-    let config = ClientConfig::new();
-    let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-    let config_path: PathBuf = [home.as_str(), ".local/share/upsync/config.json"]
+use crate::client;
+use crate::config::{self, Config, Format};
+use crate::core::{self, Behaviour, ClientConfig};
+use crate::secret::{self, Secret, Store};
+use crate::Error;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+// Every question the wizard asks, by ClientConfig field. Each one can be
+// answered with --<field> (underscores written as dashes) or in the answers
+// file.
+const FIELDS: [&str; 9] = [
+    "user",
+    "key",
+    "ip",
+    "name",
+    "wake",
+    "mac_address",
+    "default_delay",
+    "default_behaviour",
+    "popup",
+];
+
+pub fn server_setup(args: &[String]) -> Result<(), Error> {
+    let path = core::get_flag(args, "--config").map_or_else(config::config_path, PathBuf::from);
+
+    let mut answers = match core::get_flag(args, "--answers") {
+        Some(file) => read_answers(Path::new(file))?,
+        None => BTreeMap::new(),
+    };
+    for field in FIELDS {
+        if let Some(value) = core::get_flag(args, &flag(field)) {
+            answers.insert(field.to_string(), value.to_string());
+        }
+    }
+
+    let store = match core::get_flag(args, "--secret-store") {
+        Some(store) => store.parse()?,
+        None => secret::safest_store(),
+    };
+
+    let yes = args.iter().any(|arg| arg == "--yes");
+    let stdin = io::stdin();
+    let mut wizard = Wizard::new(stdin.lock(), io::stdout())
+        .answers(answers)
+        .assume_defaults(yes);
+
+    // The new client is added to the existing config unless --replace is given.
+    let replace = args.iter().any(|arg| arg == "--replace");
+    let existing = match existing_config(&path) {
+        Some(_) if replace => {
+            let overwrite = yes
+                || wizard.confirm(
+                    "Are you sure you want to delete the existing config and start creating a new config? (y/n)",
+                )?;
+            if !overwrite {
+                return Err(Error::Cancelled);
+            }
+            None
+        }
+        Some(existing) => Some(config::load(&existing).map_err(|err| {
+            Error::Config(format!(
+                "{}\nFix it with `{} config edit` or start over with --replace",
+                err,
+                core::APPNAME
+            ))
+        })?),
+        None => None,
+    };
+
+    let mut client = wizard.client_config()?;
+    let known = existing
+        .as_ref()
+        .is_some_and(|config| config.clients.iter().any(|c| c.name == client.name));
+    if known && !yes {
+        let prompt = format!("Replace the existing client {}? (y/n)", client.name);
+        if !wizard.confirm(&prompt)? {
+            return Err(Error::Cancelled);
+        }
+    }
+    if !args.iter().any(|arg| arg == "--skip-checks") {
+        let checks = discover(&mut client);
+        print_summary(&client, &checks);
+        if !yes && !wizard.confirm("Save this config? (y/n)")? {
+            return Err(Error::Cancelled);
+        }
+    }
+
+    gen_json(&path, existing, client, store)
+}
+
+// The config the new client joins: the target file, or when there is none
+// yet, the old ~/.local/share/upsync one that is about to be retired.
+fn existing_config(path: &Path) -> Option<PathBuf> {
+    if path.exists() {
+        return Some(path.to_path_buf());
+    }
+    ["config.json", "config.toml"]
+        .into_iter()
+        .map(|name| config::legacy_config_dir().join(name))
+        .find(|legacy| legacy.exists())
+}
+
+struct Check {
+    name: &'static str,
+    // Detail on success, reason on failure.
+    result: Result<String, String>,
+}
+
+// Tries the answers against the real client and fills in what can be read from
+// it. Nothing here is fatal: the client may simply be switched off.
+fn discover(config: &mut ClientConfig) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let reachable = matches!(core::device_status(&config.ip), Ok(true));
+    checks.push(Check {
+        name: "reachable",
+        result: match reachable {
+            true => Ok(config.ip.clone()),
+            false => Err(format!("nothing answers on {}", config.ip)),
+        },
+    });
+    if !reachable {
+        return checks;
+    }
+
+    let remote = match client::connect(config) {
+        Ok(remote) => remote,
+        Err(err) => {
+            checks.push(Check {
+                name: "ssh login",
+                result: Err(err.to_string()),
+            });
+            return checks;
+        }
+    };
+    checks.push(Check {
+        name: "ssh login",
+        result: Ok(config.user.clone()),
+    });
+
+    checks.push(Check {
+        name: core::GUI_APPNAME,
+        result: remote
+            .output(&format!("command -v {}", core::GUI_APPNAME))
+            .map_err(|_| format!("{} is not installed on the client", core::GUI_APPNAME)),
+    });
+
+    // The command for the default behaviour, without running it: its program
+    // has to exist and sudo has to allow it without a password.
+    let action = core::get_default_server(&config.default_behaviour);
+    if let Some(command) = config.commands.command(&action) {
+        let check = match command.strip_prefix("sudo -n ") {
+            Some(command) => format!("sudo -n -l {}", command),
+            None => format!(
+                "command -v {}",
+                command.split_whitespace().next().unwrap_or_default()
+            ),
+        };
+        checks.push(Check {
+            name: "power command",
+            result: remote
+                .output(&check)
+                .map(|_| command.clone())
+                .map_err(|err| format!("'{}' can't run on the client: {}", command, err)),
+        });
+    }
+
+    // The MAC of the interface the client would use to reach us is the one
+    // Wake-on-LAN packets arrive on.
+    let mac = remote
+        .output(&format!(
+            "cat /sys/class/net/$(ip -o route get {} | sed -n 's/.* dev \\([^ ]*\\).*/\\1/p')/address",
+            remote.local_ip
+        ))
+        .map_err(|err| err.to_string());
+    checks.push(Check {
+        name: "mac address",
+        result: match (&mac, config.mac_address.is_empty()) {
+            (Ok(mac), true) if config.wake => {
+                config.mac_address = mac.clone();
+                Ok(format!("{} (filled in)", mac))
+            }
+            (Ok(mac), false) if !mac.eq_ignore_ascii_case(&config.mac_address) => Err(format!(
+                "configured {} but the client reports {}",
+                config.mac_address, mac
+            )),
+            (Ok(mac), _) => Ok(mac.clone()),
+            (Err(err), _) => Err(err.clone()),
+        },
+    });
+
+    let action = core::get_default_server(&config.default_behaviour);
+    checks.push(Check {
+        name: "power actions",
+        result: match remote.capabilities() {
+            Ok(capabilities) => {
+                client::cache_capabilities(config, &capabilities);
+                let actions: Vec<&str> = capabilities
+                    .actions()
+                    .into_iter()
+                    .filter(|action| client::available(config, &capabilities, action))
+                    .collect();
+                let actions = actions.join(", ");
+                match action.is_empty() || client::available(config, &capabilities, &action) {
+                    true => Ok(actions),
+                    false => Err(format!(
+                        "{:?} is not supported or allowed, the client offers {}",
+                        config.default_behaviour, actions
+                    )),
+                }
+            }
+            Err(err) => Err(format!("could not ask logind: {}", err)),
+        },
+    });
+
+    checks
+}
+
+fn print_summary(config: &ClientConfig, checks: &[Check]) {
+    println!("\nClient {}:", config.name);
+    println!("    address:   {}", config.ip);
+    println!("    user:      {}", config.user);
+    println!(
+        "    behaviour: {:?} after {} seconds",
+        config.default_behaviour, config.default_delay
+    );
+    println!("    popup:     {}", config.popup);
+    println!("    wake:      {} {}", config.wake, config.mac_address);
+    println!("\nChecks:");
+    for check in checks {
+        match &check.result {
+            Ok(detail) => println!("    [ OK ] {}: {}", check.name, detail),
+            Err(reason) => println!("    [FAIL] {}: {}", check.name, reason),
+        }
+    }
+}
+
+fn store_name(store: Store) -> &'static str {
+    match store {
+        Store::Plain => "plain text in the config",
+        Store::File => "a private secrets file",
+        Store::Credential => "an encrypted systemd credential",
+        Store::Keyring => "a keyring entry",
+        Store::Age => "an age encrypted file",
+    }
+}
+
+fn flag(field: &str) -> String {
+    format!("--{}", field.replace('_', "-"))
+}
+
+// The answers file is a flat TOML or JSON table keyed by field name.
+fn read_answers(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    let data = fs::read_to_string(path)?;
+    let value: Value = match Format::of(path) {
+        Format::Json => serde_json::from_str(&data).map_err(|e| Error::Input(e.to_string()))?,
+        Format::Toml => toml::from_str(&data).map_err(|e| Error::Input(e.to_string()))?,
+    };
+
+    let table = value
+        .as_object()
+        .ok_or_else(|| Error::Input(format!("{}: expected a table", path.display())))?;
+    table
         .iter()
+        .map(|(field, value)| {
+            if !FIELDS.contains(&field.as_str()) {
+                return Err(Error::Input(format!(
+                    "{}: unknown field '{}'",
+                    path.display(),
+                    field
+                )));
+            }
+            let value = match value {
+                Value::String(value) => value.clone(),
+                other => other.to_string(),
+            };
+            Ok((field.clone(), value))
+        })
+        .collect()
+}
+
+fn gen_json(
+    path: &Path,
+    existing: Option<Config>,
+    client: ClientConfig,
+    store: Store,
+) -> Result<(), Error> {
+    let mut config = existing.unwrap_or_else(|| Config::new(Vec::new()));
+    let name = client.name.clone();
+    let added = match config.clients.iter_mut().find(|c| c.name == name) {
+        Some(old) => {
+            *old = client;
+            false
+        }
+        None => {
+            config.clients.push(client);
+            true
+        }
+    };
+    let problems = config::validate(&config);
+    if !problems.is_empty() {
+        let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+        return Err(Error::Input(problems.join("\n")));
+    }
+
+    // A password typed in is moved out of the config; a reference such as
+    // env:VAR given as the answer is kept as it is. Other clients are left
+    // as they were.
+    for client in config.clients.iter_mut().filter(|c| c.name == name) {
+        if let Secret::Plain(password) = Secret::parse(&client.key)? {
+            let password = password.to_string();
+            client.key = secret::store(store, &client.name, &password)?;
+            println!(
+                "Password for {} stored as {}",
+                client.name,
+                store_name(store)
+            );
+        }
+    }
+    config::save(path, &config)?;
+    match added {
+        true => println!("Added {} to {}", name, path.display()),
+        false => println!("Updated {} in {}", name, path.display()),
+    }
+    retire_legacy_config()
+}
+
+// The old ~/.local/share/upsync file is still read as the lowest layer, so
+// its clients would be merged back into the new config.
+fn retire_legacy_config() -> Result<(), Error> {
+    retire_configs_in(&config::legacy_config_dir())
+}
+
+// Renames config.json and config.toml to config.json.old and config.toml.old,
+// numbered when an earlier backup is in the way.
+fn retire_configs_in(dir: &Path) -> Result<(), Error> {
+    for name in ["config.json", "config.toml"] {
+        let legacy = dir.join(name);
+        if !legacy.exists() {
+            continue;
+        }
+        let old = (0..)
+            .map(|i| match i {
+                0 => dir.join(format!("{}.old", name)),
+                i => dir.join(format!("{}.old.{}", name, i)),
+            })
+            .find(|old| !old.exists())
+            .unwrap_or_default();
+        fs::rename(&legacy, &old)?;
+        println!("Moved old config {} to {}", legacy.display(), old.display());
+    }
+    Ok(())
+}
+
+// Asks the setup questions on any reader/writer pair. Questions that already
+// have an answer are not asked; an invalid answer fails right away instead of
+// being asked again.
+pub struct Wizard<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    answers: BTreeMap<String, String>,
+    // Take the default for unanswered questions instead of asking (--yes).
+    assume_defaults: bool,
+}
+
+impl<R: BufRead, W: Write> Wizard<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Wizard {
+            input,
+            output,
+            answers: BTreeMap::new(),
+            assume_defaults: false,
+        }
+    }
+
+    pub fn answers(mut self, answers: BTreeMap<String, String>) -> Self {
+        self.answers = answers;
+        self
+    }
+
+    pub fn assume_defaults(mut self, assume_defaults: bool) -> Self {
+        self.assume_defaults = assume_defaults;
+        self
+    }
+
+    pub fn confirm(&mut self, prompt: &str) -> Result<bool, Error> {
+        self.ask("confirm", prompt, |input| parse_yes_no(input, None))
+    }
+
+    pub fn client_config(&mut self) -> Result<ClientConfig, Error> {
+        let user = self.ask("user", "Enter the client username: ", required)?;
+        let key = self.ask("key", "Enter the user password: ", required)?;
+        let ip = self.ask("ip", "Enter the IP address of your device with the ssh port by default it is 22 (e.g., 192.168.66.99:22): )", required)?;
+        let name = match self.ask(
+            "name",
+            "Enter a name for this device: \nDefault: its IP address",
+            optional,
+        )? {
+            name if name.is_empty() => core::host_of(&ip).to_string(),
+            name => name,
+        };
+
+        Ok(ClientConfig {
+            name,
+            user,
+            key,
+            ip,
+            wake: self.ask("wake", "Do you want to wake the PC automatically when the power is restored using WOL (Wake-on-LAN)? (y/n) [Default: n]: ", |input| parse_yes_no(input, Some(false)))?,
+            mac_address: self.ask("mac_address", "Enter the MAC address of your device (Leave blank if you did not choose to enable Wake-on-LAN): ", optional)?,
+            default_delay: self.ask("default_delay", "Enter the time (in seconds) after power loss to put the device to default behaviour: \nDefault: 30", parse_delay)?,
+            default_behaviour: self.ask("default_behaviour", "Default behaviour when power is out: \n1 = Sleep\n2 = Hybernate\n3 = Shutdown\n4 = Do nothing \nDefault: 1 ", parse_behaviour)?,
+            popup: self.ask("popup", "Do you want to see the popup when power is out? (y/n): \nDefault: y", |input| parse_yes_no(input, Some(true)))?,
+            ..ClientConfig::default()
+        })
+    }
+
+    fn ask<T>(
+        &mut self,
+        field: &str,
+        prompt: &str,
+        parse: impl Fn(&str) -> Result<T, &'static str>,
+    ) -> Result<T, Error> {
+        let unanswered = || {
+            Error::Input(format!(
+                "{}: no answer given; pass {} or add it to the --answers file",
+                field,
+                flag(field)
+            ))
+        };
+
+        if let Some(answer) = self.answers.get(field) {
+            return parse(answer.trim()).map_err(|msg| Error::Input(format!("{}: {}", field, msg)));
+        }
+        if self.assume_defaults {
+            return parse("").map_err(|_| unanswered());
+        }
+
+        let mut attempts = 0;
+
+        while attempts < 3 {
+            writeln!(self.output, "{}", prompt)?;
+            let mut input = String::new();
+            if self.input.read_line(&mut input)? == 0 {
+                return Err(unanswered());
+            }
+
+            match parse(input.trim()) {
+                Ok(value) => return Ok(value),
+                Err(msg) => {
+                    writeln!(self.output, "{}", msg)?;
+                    attempts += 1;
+                }
+            }
+        }
+
+        Err(Error::Input("Exceeded maximum attempts.".to_string()))
+    }
+}
+
+fn required(input: &str) -> Result<String, &'static str> {
+    match input.is_empty() {
+        true => Err("Invalid input."),
+        false => Ok(input.to_string()),
+    }
+}
+
+fn optional(input: &str) -> Result<String, &'static str> {
+    Ok(input.to_string())
+}
+
+// Pressing Enter picks the default, if there is one.
+fn parse_yes_no(input: &str, default: Option<bool>) -> Result<bool, &'static str> {
+    match (input.to_lowercase().as_str(), default) {
+        ("", Some(default)) => Ok(default),
+        ("y" | "yes" | "true", _) => Ok(true),
+        ("n" | "no" | "false", _) => Ok(false),
+        _ => Err("Invalid input. Please enter 'y' for yes or 'n' for no."),
+    }
+}
+
+fn parse_delay(input: &str) -> Result<u32, &'static str> {
+    match input {
+        "" => Ok(30),
+        input => input
+            .parse()
+            .map_err(|_| "Invalid input. Please enter a valid number of seconds as an integer."),
+    }
+}
+
+// Accepts the menu number or the behaviour name.
+fn parse_behaviour(input: &str) -> Result<Behaviour, &'static str> {
+    match input.to_lowercase().as_str() {
+        "" | "1" | "sleep" => Ok(Behaviour::Sleep),
+        "2" | "hibernate" => Ok(Behaviour::Hibernate),
+        "3" | "shutdown" => Ok(Behaviour::Shutdown),
+        "4" | "ignore" => Ok(Behaviour::Ignore),
+        _ => Err("Invalid input. Please enter a choice between 1 and 4."),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn wizard(input: &str) -> Wizard<&[u8], Vec<u8>> {
+        Wizard::new(input.as_bytes(), Vec::new())
+    }
+
+    #[test]
+    fn asks_every_question() {
+        let mut wizard = wizard("dhanu\nsecret\n10.0.0.2:22\n\ny\naa:bb:cc:dd:ee:ff\n\n2\nn\n");
+        let client = wizard.client_config().unwrap();
+
+        assert_eq!(client.name, "10.0.0.2");
+        assert_eq!(client.user, "dhanu");
+        assert!(client.wake);
+        assert_eq!(client.default_delay, 30);
+        assert_eq!(client.default_behaviour, Behaviour::Hibernate);
+        assert!(!client.popup);
+        assert!(String::from_utf8(wizard.output)
+            .unwrap()
+            .contains("Enter the client username"));
+    }
+
+    #[test]
+    fn retries_invalid_input_three_times() {
+        let mut wizard = wizard("dhanu\nsecret\n10.0.0.2:22\ndesktop\nmaybe\nperhaps\nsure\n");
+        assert!(matches!(wizard.client_config(), Err(Error::Input(_))));
+        let output = String::from_utf8(wizard.output).unwrap();
+        assert_eq!(output.matches("Please enter 'y' for yes").count(), 3);
+    }
+
+    #[test]
+    fn answers_skip_the_prompts() {
+        let answers = [
+            ("user", "dhanu"),
+            ("key", "secret"),
+            ("ip", "10.0.0.2:22"),
+            ("default_behaviour", "shutdown"),
+        ]
+        .iter()
+        .map(|(field, value)| (field.to_string(), value.to_string()))
         .collect();
+        let client = wizard("")
+            .answers(answers)
+            .assume_defaults(true)
+            .client_config()
+            .unwrap();
+
+        assert_eq!(client.name, "10.0.0.2");
+        assert_eq!(client.default_behaviour, Behaviour::Shutdown);
+        assert!(client.popup);
+    }
+
+    #[test]
+    fn fails_on_missing_required_answer() {
+        let mut defaults = wizard("").assume_defaults(true);
+        let result = defaults.client_config();
+        assert!(matches!(result, Err(Error::Input(msg)) if msg.starts_with("user")));
+
+        // Without --yes the wizard prompts, but there is no more input.
+        let mut prompts = wizard("dhanu\n");
+        let result = prompts.client_config();
+        assert!(matches!(result, Err(Error::Input(msg)) if msg.starts_with("key")));
+    }
 
-    create_dir_all(config_path.parent().unwrap()).unwrap();
+    #[test]
+    fn keeps_every_legacy_backup() {
+        let dir = std::env::temp_dir().join(format!("upsync-legacy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("config.json.old"), "earlier").unwrap();
+        fs::write(dir.join("config.json"), "json").unwrap();
+        fs::write(dir.join("config.toml"), "toml").unwrap();
 
-    println!("File created successfully!");
-    let file = File::create(config_path).unwrap();
-    to_writer(file, &config).unwrap();
+        retire_configs_in(&dir).unwrap();
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("config.json.old"), "earlier");
+        assert_eq!(read("config.json.old.1"), "json");
+        assert_eq!(read("config.toml.old"), "toml");
+        assert!(!dir.join("config.json").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_answer() {
+        let answers = BTreeMap::from([("user".to_string(), String::new())]);
+        let mut wizard = wizard("").answers(answers);
+        assert!(
+            matches!(wizard.client_config(), Err(Error::Input(msg)) if msg.starts_with("user"))
+        );
+    }
 }
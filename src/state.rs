@@ -0,0 +1,136 @@
+use crate::{config, core, Error};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Where an outage stands, saved after every step so a restarted server
+// continues instead of starting over.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Phase {
+    #[default]
+    Mains,
+    OnBattery,
+    // Power is back but not every client has been woken yet.
+    Restoring,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientState {
+    // Whether the client answered when the outage began.
+    #[serde(default)]
+    pub online_before: Option<bool>,
+    // Unix seconds of the attempt to act on the client, successful or not.
+    #[serde(default)]
+    pub acted_at: Option<u64>,
+    // What was sent ("popup" or the power state the client went down in),
+    // None if it failed.
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub woken_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutageState {
+    pub phase: Phase,
+    // Unix seconds of the last phase change.
+    #[serde(default)]
+    pub since: u64,
+    // By client name; cleared when the next outage starts.
+    #[serde(default)]
+    pub clients: BTreeMap<String, ClientState>,
+}
+
+impl OutageState {
+    // A missing file is a fresh start; an unreadable one is reported and
+    // ignored rather than keeping the server from starting.
+    pub fn load(path: &Path) -> OutageState {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(_) => return OutageState::default(),
+        };
+        match serde_json::from_str(&data) {
+            Ok(state) => {
+                debug!("Resuming state from {}", path.display());
+                state
+            }
+            Err(err) => {
+                warn!("Ignoring invalid state file {}: {}", path.display(), err);
+                OutageState::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let data = serde_json::to_string_pretty(self).map_err(io::Error::from)?;
+        config::write_atomic(path, &data)?;
+        Ok(())
+    }
+
+    pub fn enter(&mut self, phase: Phase) {
+        if phase == Phase::OnBattery {
+            self.clients.clear();
+        }
+        self.phase = phase;
+        self.since = now();
+    }
+
+    pub fn client(&mut self, name: &str) -> &mut ClientState {
+        self.clients.entry(name.to_string()).or_default()
+    }
+
+    pub fn acted(&self, name: &str) -> bool {
+        self.clients
+            .get(name)
+            .is_some_and(|client| client.acted_at.is_some())
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+// $XDG_STATE_HOME/upsync/state.json, usually ~/.local/state/upsync/.
+pub fn default_path() -> PathBuf {
+    let dir = match env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => config::home_dir().join(".local/state"),
+    };
+    dir.join(core::APPNAME).join("state.json")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn survives_a_restart() {
+        let path = env::temp_dir().join(format!("upsync-state-{}.json", std::process::id()));
+        let mut state = OutageState::default();
+        state.enter(Phase::OnBattery);
+        state.client("desktop").acted_at = Some(now());
+        state.client("desktop").action = Some("suspend".to_string());
+        state.save(&path).unwrap();
+
+        let mut resumed = OutageState::load(&path);
+        assert_eq!(resumed, state);
+        assert!(resumed.acted("desktop"));
+        assert!(!resumed.acted("nas"));
+
+        resumed.enter(Phase::OnBattery);
+        assert!(!resumed.acted("desktop"));
+        fs::remove_file(path).unwrap();
+    }
+}